@@ -18,7 +18,24 @@ static_files_location = "/home/simon/repos/zk_vue/dist/"
 repo_files_location = "/home/simon/repos/notes/"
 # Hostname of the server
 hostname = "localhost"
-# Start password for the "admin" user
+# Identity the server commits changes with. The author is the user that made them.
+# committer_name = "ZK"
+# committer_email = "zk@localhost"
+# If set, all repositories with a remote are synced with it every sync_interval seconds
+# sync_interval = 3600
+# If set, saves are committed together once nothing changed for commit_delay seconds
+# commit_delay = 60
+# Location of the user accounts. Defaults to users.json next to repo_files_location
+# users_file = "/home/simon/repos/users.json"
+# Location of the keys tokens are signed with. Defaults to jwt_keys.json next to repo_files_location
+# jwt_keys_file = "/home/simon/repos/jwt_keys.json"
+# Location of the key private cookies are encrypted with, unless secret_key is set in Rocket.toml.
+# Defaults to cookie_key next to repo_files_location
+# cookie_key_file = "/home/simon/repos/cookie_key"
+# Location of the tokens revoked before they expired. Defaults to revoked_tokens.json next to repo_files_location
+# revoked_tokens_file = "/home/simon/repos/revoked_tokens.json"
+# Start password for the "admin" user, and for all existing users when the accounts are first created.
+# Must be set for the first start, the server refuses to create accounts with an empty password.
 admin_password = """#;
 
 const ROCKET_CFG: &str = r#"# Config File, generated at build time.
@@ -30,7 +47,7 @@ keep_alive = 5
 read_timeout = 5
 write_timeout = 5
 log = "normal"
-limits = { forms = 32768 }
+limits = { forms = 32768, data-form = 10485760, file = 10485760 }
 
 [staging]
 address = "0.0.0.0"
@@ -40,7 +57,7 @@ keep_alive = 5
 read_timeout = 5
 write_timeout = 5
 log = "normal"
-limits = { forms = 32768 }
+limits = { forms = 32768, data-form = 10485760, file = 10485760 }
 
 [production]
 address = "localhost"
@@ -50,7 +67,7 @@ keep_alive = 5
 read_timeout = 5
 write_timeout = 5
 log = "critical"
-limits = { forms = 32768 }"#;
+limits = { forms = 32768, data-form = 10485760, file = 10485760 }"#;
 
 fn main() {
     // println!("cargo:rerun-if-changed=config.json");
@@ -1,4 +1,5 @@
 use crate::filesystem_interact::FType;
+use rocket::fs::TempFile;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct AuthAttempt {
@@ -6,9 +7,79 @@ pub(crate) struct AuthAttempt {
     pub(crate) password: String,
 }
 
-#[allow(dead_code)] // TODO: Implement
+#[derive(Deserialize)]
+pub(crate) struct CommitAttempt {
+    pub(crate) message: Option<String>, // Subject of the commit, the changed paths are appended
+}
+
 #[derive(Deserialize)]
 pub(crate) struct CreateAttempt {
-    ftype: FType,
-    options: String,
+    pub(crate) ftype: FType,
+    pub(crate) options: String, // Name of the new file or directory
+}
+
+#[derive(FromForm)]
+pub(crate) struct UploadAttempt<'r> {
+    pub(crate) file: TempFile<'r>,
+    pub(crate) name: Option<String>, // Defaults to the name of the uploaded file
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MoveAttempt {
+    pub(crate) target: String, // New path, relative to the users directory
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ProfileAttempt {
+    pub(crate) name: Option<String>,
+    pub(crate) email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RemoteAttempt {
+    pub(crate) url: Option<String>, // Removes the remote if not set
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>, // Password or access token, for https remotes
+    pub(crate) ssh_key: Option<String>,  // Private key, for ssh remotes
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RestoreAttempt {
+    pub(crate) id: String,
+    pub(crate) target: Option<String>, // Defaults to the original path
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserAttempt {
+    pub(crate) name: String,
+    pub(crate) password: String,
+    #[serde(default)]
+    pub(crate) admin: bool,
+}
+
+// Only the fields that are set are changed
+#[derive(Deserialize)]
+pub(crate) struct UserUpdateAttempt {
+    pub(crate) password: Option<String>,
+    pub(crate) disabled: Option<bool>,
+    pub(crate) admin: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SnapshotAttempt {
+    pub(crate) name: String,
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) enum PatchAttempt {
+    Diff(String),                // Unified diff, file headers are optional and ignored
+    Lines(Vec<LineReplacement>), // Replacements of line ranges
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LineReplacement {
+    pub(crate) start: usize, // First line to replace, starting at 1
+    pub(crate) end: usize, // Last line to replace (inclusive). end = start - 1 inserts before start
+    pub(crate) content: String,
 }
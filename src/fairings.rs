@@ -1,3 +1,8 @@
+use crate::functions::commit_idle;
+use crate::functions::commit_leftovers;
+use crate::functions::sync_all;
+use crate::state::PendingCommits;
+use crate::state::ZKConfig;
 use rocket::fairing::Fairing;
 use rocket::fairing::Info;
 use rocket::fairing::Kind;
@@ -5,6 +10,11 @@ use rocket::http::ContentType;
 use rocket::http::Header;
 use rocket::request::Request;
 use rocket::response::Response;
+use rocket::tokio::task;
+use rocket::tokio::time;
+use rocket::Orbit;
+use rocket::Rocket;
+use std::time::Duration;
 
 pub(crate) struct Gzip;
 
@@ -98,13 +108,14 @@ impl Fairing for CORS {
         ));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
-            "POST, GET, PUT, POST, PATCH, OPTIONS",
+            "POST, GET, PUT, POST, PATCH, DELETE, OPTIONS",
         ));
         response.set_header(Header::new("Access-Control-Max-Age", "86400"));
         response.set_header(Header::new(
             "Access-Control-Allow-Headers",
-            "Cookie, Content-Type",
+            "Cookie, Content-Type, If-Match",
         ));
+        response.set_header(Header::new("Access-Control-Expose-Headers", "ETag"));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
     }
 }
@@ -130,3 +141,88 @@ impl Fairing for XFRameOptions {
         }
     }
 }
+
+// Periodically syncs all repositories with their remotes
+pub(crate) struct RemoteSync {
+    pub(crate) interval: u64,
+}
+
+#[rocket::async_trait]
+impl Fairing for RemoteSync {
+    fn info(&self) -> Info {
+        Info {
+            name: "Remote sync",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let consts = match rocket.state::<ZKConfig>() {
+            Some(c) => c.clone(),
+            None => return,
+        };
+        let mut interval = time::interval(Duration::from_secs(self.interval));
+        rocket::tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let consts = consts.clone();
+                task::spawn_blocking(move || sync_all(&consts)).await.ok();
+            }
+        });
+    }
+}
+
+// Commits the pending changes of users once they stopped editing for a while
+pub(crate) struct CommitBatching {
+    pub(crate) delay: u64,
+}
+
+#[rocket::async_trait]
+impl Fairing for CommitBatching {
+    fn info(&self) -> Info {
+        Info {
+            name: "Commit batching",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (consts, pending) = match (rocket.state::<ZKConfig>(), rocket.state::<PendingCommits>())
+        {
+            (Some(c), Some(p)) => (c.clone(), p.clone()),
+            _ => return,
+        };
+        let delay = Duration::from_secs(self.delay);
+        let mut interval = time::interval(Duration::from_secs(1));
+        rocket::tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let (consts, pending) = (consts.clone(), pending.clone());
+                task::spawn_blocking(move || commit_idle(&consts, &pending, delay))
+                    .await
+                    .ok();
+            }
+        });
+    }
+}
+
+// Commits changes that were not committed before the server stopped
+pub(crate) struct LeftoverCommits;
+
+#[rocket::async_trait]
+impl Fairing for LeftoverCommits {
+    fn info(&self) -> Info {
+        Info {
+            name: "Leftover commits",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let consts = match rocket.state::<ZKConfig>() {
+            Some(c) => c.clone(),
+            None => return,
+        };
+        rocket::tokio::spawn(task::spawn_blocking(move || commit_leftovers(&consts)));
+    }
+}
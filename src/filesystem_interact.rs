@@ -1,8 +1,18 @@
+use crate::deserializables::LineReplacement;
+use crate::links::normalize;
+use crypto_hashes::sha2::{Digest, Sha256};
 use rocket::serde::json::serde_json::json;
 use rocket::serde::json::Value;
+use std::cmp::Reverse;
+use std::fs::create_dir;
+use std::fs::read;
 use std::fs::read_dir;
+use std::fs::rename;
 use std::fs::DirEntry;
+use std::fs::OpenOptions;
 use std::io;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
 
 #[derive(Serialize)]
@@ -10,14 +20,20 @@ pub(crate) struct Directory {
     head: Entry,
     mds: Vec<Entry>,
     dirs: Vec<Entry>,
+    attachments: Vec<Entry>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub(crate) enum FType {
     MDFile,
     Directory,
+    Attachment,
 }
 
+// Binary files that can be uploaded and referenced from notes
+pub(crate) const ATTACHMENT_EXTENSIONS: [&str; 7] =
+    ["png", "jpg", "jpeg", "gif", "webp", "bmp", "pdf"];
+
 #[derive(Serialize)]
 pub(crate) struct Entry {
     pub(crate) name: String,
@@ -31,6 +47,26 @@ impl Entry {
     pub(crate) fn json(&self) -> Value {
         json!(self)
     }
+
+    // Sha256-Hash of the file content, or of the (visible) entry names for directories. Unlike the
+    // hash of the response body, this does not change with the access time of the file.
+    pub(crate) fn etag(&self) -> Option<String> {
+        let mut hasher = Sha256::new();
+        match self.ftype {
+            FType::MDFile | FType::Attachment => hasher.update(read(&self.data).ok()?),
+            FType::Directory => {
+                let mut names: Vec<String> = read_dir(&self.data)
+                    .ok()?
+                    .filter_map(|e| e.ok())
+                    .filter(|e| !is_hidden(e))
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect();
+                names.sort();
+                hasher.update(names.join("\n").as_bytes());
+            }
+        }
+        Some(format!("{:x}", hasher.finalize()))
+    }
 }
 
 impl Directory {
@@ -48,6 +84,7 @@ pub(crate) fn ls(
 ) -> io::Result<Directory> {
     let mut mds: Vec<Entry> = Vec::new();
     let mut dirs: Vec<Entry> = Vec::new();
+    let mut attachments: Vec<Entry> = Vec::new();
     for entry in read_dir(&entry.data)?
         .filter(|e| e.is_ok())
         .filter_map(|e| e.ok())
@@ -61,6 +98,7 @@ pub(crate) fn ls(
             match e.ftype {
                 FType::MDFile => mds.push(e),
                 FType::Directory => dirs.push(e),
+                FType::Attachment => attachments.push(e),
             }
         }
     }
@@ -68,6 +106,7 @@ pub(crate) fn ls(
         head: entry,
         mds,
         dirs,
+        attachments,
     })
 }
 
@@ -93,10 +132,219 @@ pub(crate) fn open(url: &PathBuf, basepath: &PathBuf) -> Option<Entry> {
             ftype: FType::MDFile,
             url: url.clone(),
         }),
+        e if e.is_file() && is_attachment(&e) => Some(Entry {
+            data: e,
+            name: filename,
+            ftype: FType::Attachment,
+            url: url.clone(),
+        }),
         _ => None,
     }
 }
 
+// Creates a new file or directory called `name` inside the directory at `url`. Returns the path
+// (relative to basepath) that has to be added to the repository index.
+pub(crate) fn create(
+    url: &Path,
+    basepath: &Path,
+    name: &str,
+    ftype: &FType,
+) -> io::Result<PathBuf> {
+    let mut url = url.join(valid_name(name)?);
+    if let FType::MDFile = ftype {
+        if url.extension().unwrap_or_default() != "md" {
+            url.set_extension("md");
+        }
+    }
+    let path = basepath.join(&url);
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "File or directory already exists.",
+        ));
+    }
+    match ftype {
+        FType::MDFile => {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            Ok(url)
+        }
+        FType::Attachment => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Attachments have to be uploaded.",
+        )),
+        FType::Directory => {
+            create_dir(&path)?;
+            // Git does not track empty directories
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path.join(".gitkeep"))?;
+            Ok(url.join(".gitkeep"))
+        }
+    }
+}
+
+// Directories containing anything but hidden files may only be removed if `recursive` is set
+pub(crate) fn check_removable(entry: &Entry, recursive: bool) -> io::Result<()> {
+    if let FType::Directory = entry.ftype {
+        if !recursive
+            && read_dir(&entry.data)?
+                .filter_map(|e| e.ok())
+                .any(|e| !is_hidden(&e))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                "Directory is not empty.",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Replaces line ranges of `content`. Ranges are applied from the bottom up, so line numbers always
+// refer to the original content. Fails on overlapping or out of bounds ranges.
+pub(crate) fn replace_lines(
+    content: &str,
+    replacements: &mut [LineReplacement],
+) -> Result<String, String> {
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(String::from).collect();
+    replacements.sort_by_key(|r| Reverse(r.start));
+    let mut upper = lines.len() + 1;
+    for r in replacements.iter() {
+        if r.start == 0 || r.end + 1 < r.start || r.end >= upper {
+            return Err(format!(
+                "Line range {}-{} is out of bounds or overlaps another range.",
+                r.start, r.end
+            ));
+        }
+        let mut replacement = r.content.clone();
+        if !replacement.is_empty() && !replacement.ends_with('\n') && r.end < lines.len() {
+            replacement.push('\n');
+        }
+        // Content appended after a last line without newline starts on a line of its own
+        if !replacement.is_empty() && r.start > lines.len() {
+            if let Some(last) = lines.last_mut().filter(|l| !l.ends_with('\n')) {
+                last.push('\n');
+            }
+        }
+        lines.splice(
+            r.start - 1..r.end,
+            replacement.split_inclusive('\n').map(String::from),
+        );
+        upper = r.start;
+    }
+    Ok(lines.concat())
+}
+
+// Moves a file or directory to `target` (relative to basepath). Notes keep their extension.
+// Returns the normalized target path.
+pub(crate) fn move_entry(entry: &Entry, target: &str, basepath: &Path) -> io::Result<PathBuf> {
+    let target = normalize(Path::new(target.trim_start_matches('/')))
+        .filter(|t| t.components().next().is_some() && !is_hidden_path(t))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid target path."))?;
+    let target = keep_extension(target, &entry.data, &entry.ftype);
+    let path = basepath.join(&target);
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "File or directory already exists.",
+        ));
+    }
+    if path.starts_with(&entry.data) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A directory can't be moved into itself.",
+        ));
+    }
+    if !path.parent().is_some_and(|p| p.is_dir()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Target directory does not exist.",
+        ));
+    }
+    rename(&entry.data, &path)?;
+    Ok(target)
+}
+
+// Files keep their extension when they are moved or restored, so notes stay notes
+pub(crate) fn keep_extension(mut target: PathBuf, original: &Path, ftype: &FType) -> PathBuf {
+    let extension = original.extension().unwrap_or_default();
+    if !matches!(ftype, FType::Directory) && target.extension() != Some(extension) {
+        let mut name = target.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(extension);
+        target.set_file_name(name);
+    }
+    target
+}
+
+// Lists all files below `url` recursively, relative to basepath. The .git directory is always
+// skipped, other hidden files only if `hidden` is not set.
+pub(crate) fn list_files(url: &Path, basepath: &Path, hidden: bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let path = basepath.join(url);
+    if path.is_file() {
+        files.push(url.to_path_buf());
+        return files;
+    }
+    if let Ok(entries) = read_dir(&path) {
+        for e in entries.filter_map(|e| e.ok()) {
+            if e.file_name() == ".git" || (is_hidden(&e) && !hidden) {
+                continue;
+            }
+            files.append(&mut list_files(&url.join(e.file_name()), basepath, hidden));
+        }
+    }
+    files
+}
+
+// Returns the path (relative to basepath) an attachment called `name` can be stored at, inside the
+// directory at `url`
+pub(crate) fn attachment_path(url: &Path, basepath: &Path, name: &str) -> io::Result<PathBuf> {
+    let url = url.join(valid_name(name)?);
+    if !is_attachment(&url) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "File type is not allowed.",
+        ));
+    }
+    if basepath.join(&url).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "File or directory already exists.",
+        ));
+    }
+    Ok(url)
+}
+
+pub(crate) fn valid_name(name: &str) -> io::Result<&Path> {
+    let name = Path::new(name);
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(n)), None) if !n.to_string_lossy().starts_with(".") => Ok(name),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid name.")),
+    }
+}
+
+fn is_attachment(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    ATTACHMENT_EXTENSIONS.contains(&extension.as_str())
+}
+
+pub(crate) fn is_hidden_path(url: &Path) -> bool {
+    url.components().any(|c| match c {
+        Component::Normal(n) => n.to_string_lossy().starts_with("."),
+        _ => false,
+    })
+}
+
 fn is_hidden(entry: &DirEntry) -> bool {
     if entry
         .path()
@@ -139,7 +387,7 @@ mod entry_serialization {
         if metadata.is_dir() {
             set_file_atime(&entry, FileTime::now()).ok();
             // TODO: Serialize Number of Items...
-        } else {
+        } else if entry.extension().unwrap_or_default() == "md" {
             let c = read_to_string(entry).ok();
             state.serialize_field("content", &c)?;
             set_file_atime(&entry, FileTime::now()).ok();
@@ -152,3 +400,72 @@ mod entry_serialization {
         t.to_rfc2822()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::replace_lines;
+    use crate::deserializables::LineReplacement;
+
+    fn replace(content: &str, start: usize, end: usize, replacement: &str) -> String {
+        let mut replacements = [LineReplacement {
+            start,
+            end,
+            content: replacement.to_string(),
+        }];
+        replace_lines(content, &mut replacements).unwrap()
+    }
+
+    #[test]
+    fn append() {
+        assert_eq!(replace("a\n", 2, 1, "b"), "a\nb");
+        assert_eq!(replace("a\n", 2, 1, "b\n"), "a\nb\n");
+        assert_eq!(replace("a", 2, 1, "b"), "a\nb");
+        assert_eq!(replace("a", 2, 1, "b\n"), "a\nb\n");
+        assert_eq!(replace("", 1, 0, "a"), "a");
+    }
+
+    #[test]
+    fn insert() {
+        assert_eq!(replace("a\nc\n", 2, 1, "b"), "a\nb\nc\n");
+        assert_eq!(replace("a\nc", 2, 1, "b"), "a\nb\nc");
+        assert_eq!(replace("b\n", 1, 0, "a\n"), "a\nb\n");
+        assert_eq!(replace("b", 1, 0, "a"), "a\nb");
+    }
+
+    #[test]
+    fn replace_range() {
+        assert_eq!(replace("a\nb\nc\n", 2, 2, "x"), "a\nx\nc\n");
+        assert_eq!(replace("a\nb\nc", 2, 3, "x\ny"), "a\nx\ny");
+        assert_eq!(replace("a\nb\nc\n", 3, 3, "x\n"), "a\nb\nx\n");
+    }
+
+    #[test]
+    fn delete() {
+        assert_eq!(replace("a\nb\nc\n", 2, 2, ""), "a\nc\n");
+        assert_eq!(replace("a\nb\nc", 3, 3, ""), "a\nb\n");
+        assert_eq!(replace("a\nb", 1, 2, ""), "");
+    }
+
+    #[test]
+    fn out_of_bounds_or_overlapping() {
+        let mut replacements = [
+            LineReplacement {
+                start: 1,
+                end: 2,
+                content: String::new(),
+            },
+            LineReplacement {
+                start: 2,
+                end: 2,
+                content: String::new(),
+            },
+        ];
+        assert!(replace_lines("a\nb\n", &mut replacements).is_err());
+        let mut replacements = [LineReplacement {
+            start: 3,
+            end: 3,
+            content: String::new(),
+        }];
+        assert!(replace_lines("a\nb\n", &mut replacements).is_err());
+    }
+}
@@ -1,21 +1,33 @@
+use crate::git_interact::CommitData;
+use crate::git_interact::Identity;
+use crate::git_interact::RepositoryTransaction;
+use crate::git_interact::SyncOutcome;
 use crate::requestguards::AuthError;
 use crate::requestguards::CSRFClaims;
 use crate::responders::ApiResponse;
+use crate::serializables::AppState;
 use crate::serializables::Claims;
 use crate::serializables::DataType;
 use crate::serializables::ResponseBodyGeneric;
 use crate::state::ApiKey;
+use crate::state::PendingCommits;
 use crate::state::ZKConfig;
+use git2::ErrorCode;
+use jsonwebtoken::errors::ErrorKind as JWTErrorKind;
+use rocket::http::Status;
 use rocket::serde::json::serde_json::json;
 use rocket::State;
 use std::collections::hash_map::DefaultHasher;
+use std::fs::read_dir;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io::ErrorKind;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 // Helpers
-#[allow(dead_code)] // Will be needed eventually... Implement it using SHA-2 for file names
-fn calculate_id<T: Hash>(t: &T) -> u64 {
+pub(crate) fn calculate_id<T: Hash>(t: &T) -> u64 {
     let salt: u64 = rand::random();
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
@@ -23,6 +35,176 @@ fn calculate_id<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
+pub(crate) fn user_basepath(consts: &ZKConfig, claims: &Claims) -> PathBuf {
+    let mut basepath = PathBuf::from(&consts.repo_files_location);
+    basepath.push(claims.get_sub());
+    basepath
+}
+
+pub(crate) fn user_repository(
+    consts: &ZKConfig,
+    claims: &Claims,
+) -> Result<RepositoryTransaction, git2::Error> {
+    repository(consts, &claims.get_sub())
+}
+
+// Opens the repository of `user`. Commits are authored with the users profile and committed with
+// the identity of the server.
+fn repository(consts: &ZKConfig, user: &str) -> Result<RepositoryTransaction, git2::Error> {
+    let basepath = PathBuf::from(&consts.repo_files_location).join(user);
+    let committer = Identity {
+        name: consts
+            .committer_name
+            .clone()
+            .unwrap_or_else(|| "ZK".to_string()),
+        email: consts
+            .committer_email
+            .clone()
+            .unwrap_or_else(|| format!("zk@{}", consts.hostname)),
+    };
+    // User directories become repositories on first use
+    let repo = match RepositoryTransaction::from(basepath.to_str().unwrap_or_default()) {
+        Err(e) if e.code() == ErrorCode::NotFound && basepath.is_dir() => {
+            RepositoryTransaction::init(basepath.to_str().unwrap_or_default(), committer.clone())?
+        }
+        r => r?,
+    };
+    let profile = repo.profile()?;
+    let author = Identity {
+        name: profile.name.unwrap_or_else(|| user.to_string()),
+        email: profile
+            .email
+            .unwrap_or_else(|| format!("{}@{}", user, consts.hostname)),
+    };
+    Ok(repo.set_identities(author, committer))
+}
+
+// Commits the change of `path` right away, or leaves it to the next batch if commits are delayed
+pub(crate) fn commit_or_defer(
+    repo: &mut RepositoryTransaction,
+    path: &Path,
+    message: &str,
+    consts: &ZKConfig,
+    pending: &PendingCommits,
+    claims: &Claims,
+) -> Result<Option<CommitData>, git2::Error> {
+    match consts.commit_delay {
+        Some(_) => {
+            pending.add(&claims.get_sub(), &[path.to_path_buf()]);
+            Ok(None)
+        }
+        None => {
+            let oid = repo.add_and_commit(path, message)?;
+            repo.find_commit(oid).map(Some)
+        }
+    }
+}
+
+// Commits all pending changes of `user`. The message lists every changed path.
+pub(crate) fn commit_pending(
+    consts: &ZKConfig,
+    pending: &PendingCommits,
+    user: &str,
+    subject: Option<&str>,
+) -> Result<Option<CommitData>, git2::Error> {
+    let paths = pending.take(user);
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let subject = match (subject, paths.as_slice()) {
+        (Some(s), _) => s.to_string(),
+        (None, [path]) => format!("Update {}", path.display()),
+        (None, _) => format!("Update {} files", paths.len()),
+    };
+    let message = format!(
+        "{}\n\nChanged:\n{}",
+        subject,
+        paths
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    let commit =
+        repository(consts, user).and_then(|mut r| match r.commit_changes(&paths, &message)? {
+            Some(oid) => r.find_commit(oid).map(Some),
+            None => Ok(None),
+        });
+    match commit {
+        Ok(_) => pending.committed(user),
+        Err(_) => pending.retry(user, &paths),
+    }
+    commit
+}
+
+// Commits the pending changes of all users that stopped editing
+pub(crate) fn commit_idle(consts: &ZKConfig, pending: &PendingCommits, delay: Duration) {
+    for user in pending.idle(delay) {
+        if let Err(e) = commit_pending(consts, pending, &user, None) {
+            warn!("Committing the changes of {} failed: {}", user, e);
+        }
+    }
+}
+
+// Commits what was left uncommitted in user repositories, e.g. because the server stopped before a
+// batch of saves was committed
+pub(crate) fn commit_leftovers(consts: &ZKConfig) {
+    let users = match read_dir(&consts.repo_files_location) {
+        Ok(u) => u,
+        Err(_) => return,
+    };
+    for user in users
+        .filter_map(|u| u.ok())
+        .filter(|u| u.path().join(".git").is_dir())
+    {
+        let user = user.file_name().to_string_lossy().to_string();
+        let commit = repository(consts, &user)
+            .and_then(|mut r| r.commit_all("Commit changes made before the server restarted"));
+        if let Err(e) = commit {
+            warn!("Committing the leftover changes of {} failed: {}", user, e);
+        }
+    }
+}
+
+// Local paths and file:// urls would let users sync with repositories on the server, including
+// those of other users
+pub(crate) fn is_remote_url(url: &str) -> bool {
+    ["https://", "ssh://"].iter().any(|scheme| {
+        url.get(..scheme.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+            && !url[scheme.len()..].starts_with('/')
+            && url.len() > scheme.len()
+    })
+}
+
+// Syncs all user repositories that have a remote configured
+pub(crate) fn sync_all(consts: &ZKConfig) {
+    let users = match read_dir(&consts.repo_files_location) {
+        Ok(u) => u,
+        Err(_) => return,
+    };
+    for user in users.filter_map(|u| u.ok()).filter(|u| u.path().is_dir()) {
+        let user = user.file_name().to_string_lossy().to_string();
+        let outcome = repository(consts, &user).and_then(|mut r| match r.remote_url()? {
+            Some(u) if !is_remote_url(&u) => Err(git2::Error::from_str("Remote is not supported")),
+            Some(_) => r.sync().map(Some),
+            None => Ok(None),
+        });
+        match outcome {
+            Ok(Some(SyncOutcome::Conflict(paths))) => warn!(
+                "Syncing the repository of {} failed, conflicts in: {}",
+                user,
+                paths.join(", ")
+            ),
+            Ok(Some(SyncOutcome::Refused(reason))) => {
+                warn!("Syncing the repository of {} failed: {}", user, reason)
+            }
+            Err(e) => warn!("Syncing the repository of {} failed: {}", user, e),
+            _ => (),
+        }
+    }
+}
+
 pub(crate) fn check_claims_csrf<'a>(
     claims: &'a Result<Claims, AuthError>,
     csrf: Option<&'a Result<CSRFClaims, AuthError>>,
@@ -65,6 +247,26 @@ pub(crate) fn handle_jwt_error(
             json!({"message": "Bad or invalid CSRF-Token."}),
             DataType::ErrorMessage,
         ),
+        // The session is still valid, so the client must not clear it
+        AuthError::NotAdmin => {
+            let res = res.set_inner(
+                json!({"message": "Only admins may do this."}),
+                DataType::ErrorMessage,
+            );
+            return ApiResponse::ok(res).set_status(Status::Forbidden);
+        }
+        // Only the access token expired, the client can get a new one with its refresh token
+        AuthError::JWTError(e) if matches!(e.kind(), JWTErrorKind::ExpiredSignature) => {
+            let res = res.set_inner(
+                json!({"message": "JWT expired. Please refresh."}),
+                DataType::ErrorMessage,
+            );
+            return ApiResponse::ok(res).set_status(Status::Unauthorized);
+        }
+        AuthError::Revoked => res.set_inner(
+            json!({"message": "JWT revoked. Please authorize."}),
+            DataType::ErrorMessage,
+        ),
         _ => res.set_inner(
             json!({"message": "JWT invalidated. Please authorize."}),
             DataType::ErrorMessage,
@@ -72,3 +274,148 @@ pub(crate) fn handle_jwt_error(
     };
     ApiResponse::unauthorized(res)
 }
+
+pub(crate) fn handle_git_error(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    error: git2::Error,
+) -> ApiResponse {
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(
+            json!({"message": "Repository error.", "details": error.message()}),
+            DataType::ErrorMessage,
+        )
+        .set_appstate(AppState::default().set_authorized(true));
+    ApiResponse::internal_server_error(res)
+}
+
+pub(crate) fn handle_io_error(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    error: std::io::Error,
+) -> ApiResponse {
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(
+            json!({"message": error.to_string()}),
+            DataType::ErrorMessage,
+        )
+        .set_appstate(AppState::default().set_authorized(true));
+    match error.kind() {
+        ErrorKind::InvalidInput => ApiResponse::bad_request(res),
+        ErrorKind::AlreadyExists | ErrorKind::DirectoryNotEmpty => ApiResponse::conflict(res),
+        ErrorKind::NotFound => ApiResponse::not_found(res),
+        _ => ApiResponse::internal_server_error(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::commit_idle;
+    use super::commit_leftovers;
+    use super::commit_or_defer;
+    use super::user_repository;
+    use crate::serializables::Claims;
+    use crate::state::PendingCommits;
+    use crate::state::ZKConfig;
+    use figment::providers::Format;
+    use figment::providers::Toml;
+    use figment::Figment;
+    use git2::Repository;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::remove_dir_all;
+    use std::fs::write;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    // Configuration with a commit delay for users in `base`, and the directory of alice
+    fn config(base: &Path) -> (ZKConfig, PathBuf) {
+        let dir = base.join("notes").join("alice");
+        create_dir_all(&dir).unwrap();
+        let config = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                path = "/api"
+                cors = false
+                repo_files_location = "{}"
+                hostname = "localhost"
+                admin_password = "secret"
+                commit_delay = 60
+                "#,
+                base.join("notes").display()
+            )))
+            .extract()
+            .unwrap();
+        (config, dir)
+    }
+
+    // Saves `content` to `file` the way the routes do
+    fn save(config: &ZKConfig, pending: &PendingCommits, dir: &Path, file: &str, content: &str) {
+        let claims = Claims::default().set_sub("alice");
+        let mut repo = user_repository(config, &claims).unwrap();
+        write(dir.join(file), content).unwrap();
+        let commit = commit_or_defer(&mut repo, Path::new(file), "Save", config, pending, &claims);
+        assert!(commit.unwrap().is_none());
+    }
+
+    fn commits(dir: &Path) -> usize {
+        let repo = Repository::open(dir).unwrap();
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_head().unwrap();
+        walk.count()
+    }
+
+    fn last_message(dir: &Path) -> String {
+        let repo = Repository::open(dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        head.message().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn batched_saves_commit_once() {
+        let base = env::temp_dir().join(format!("zk-batch-{}", std::process::id()));
+        let (config, dir) = config(&base);
+        let pending = PendingCommits::default();
+        save(&config, &pending, &dir, "a.md", "one\n");
+        let initial = commits(&dir);
+        save(&config, &pending, &dir, "b.md", "two\n");
+        save(&config, &pending, &dir, "a.md", "three\n");
+        assert_eq!(commits(&dir), initial);
+
+        // Nothing is committed while the user keeps editing
+        commit_idle(&config, &pending, Duration::from_secs(60));
+        assert_eq!(commits(&dir), initial);
+        commit_idle(&config, &pending, Duration::ZERO);
+        assert_eq!(commits(&dir), initial + 1);
+        assert_eq!(last_message(&dir), "Update 2 files\n\nChanged:\na.md\nb.md");
+        assert!(pending.idle(Duration::ZERO).is_empty());
+        commit_idle(&config, &pending, Duration::ZERO);
+        assert_eq!(commits(&dir), initial + 1);
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn leftovers_committed_after_restart() {
+        let base = env::temp_dir().join(format!("zk-leftovers-{}", std::process::id()));
+        let (config, dir) = config(&base);
+        let pending = PendingCommits::default();
+        save(&config, &pending, &dir, "a.md", "one\n");
+        let initial = commits(&dir);
+        save(&config, &pending, &dir, "b.md", "two\n");
+        // The server stops before the batch is committed
+        drop(pending);
+        create_dir_all(base.join("notes").join("bob")).unwrap();
+        commit_leftovers(&config);
+        assert_eq!(commits(&dir), initial + 1);
+        assert!(last_message(&dir).starts_with("Commit changes made before the server restarted"));
+        assert!(!base.join("notes").join("bob").join(".git").exists());
+        commit_leftovers(&config);
+        assert_eq!(commits(&dir), initial + 1);
+        remove_dir_all(&base).ok();
+    }
+}
@@ -1,35 +1,362 @@
+use crate::state::write_private;
+use crate::trash::TRASH_DIR;
 use chrono::prelude::*;
-use git2::{Commit, Error, ObjectType, Oid, Repository, Signature};
+use git2::build::CheckoutBuilder;
+use git2::{
+    ApplyLocation, Commit, ConfigLevel, Cred, CredentialType, Delta, Diff, DiffFindOptions,
+    DiffOptions, Error, ErrorClass, ErrorCode, FetchOptions, FileMode, Index, IndexAddOption,
+    ObjectType, Oid, Patch, PushOptions, RemoteCallbacks, Repository, Signature, Sort, Tree,
+    TreeWalkMode, TreeWalkResult,
+};
+use rocket::serde::json::serde_json;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::read;
+use std::fs::read_to_string;
+use std::fs::remove_file;
+use std::fs::write;
+use std::io;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::OnceLock;
+use std::sync::PoisonError;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub(crate) struct CommitData {
-    id: String,
-    time: String,
+    pub(crate) id: String,
+    pub(crate) time: String,
     author: String,
     message: String,
 }
 
-#[allow(unused)] // TODO: GIT INTEGRATION
-#[allow(dead_code)]
+// A block of lines both sides changed differently. base_start is the first line (starting at 1)
+// of the block in the version both sides started from.
+#[derive(Serialize, Debug)]
+pub(crate) struct ConflictHunk {
+    base_start: usize,
+    base: String,
+    ours: String,
+    theirs: String,
+}
+
+// A hunk of a diff between two versions of a note. `words` is the diff of the hunks old and new
+// text word by word, for highlighting changes within lines.
+#[derive(Serialize, Debug)]
+pub(crate) struct DiffHunk {
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+    lines: Vec<DiffLine>,
+    words: Vec<DiffWord>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DiffLine {
+    origin: char, // '+', '-' or ' '
+    old_lineno: Option<u32>,
+    new_lineno: Option<u32>,
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DiffWord {
+    origin: char,
+    content: String,
+}
+
+// Lines `start` to `start + lines - 1` (starting at 1) were last changed in `commit`
+#[derive(Serialize, Debug)]
+pub(crate) struct BlameHunk {
+    start: usize,
+    lines: usize,
+    commit: CommitData,
+}
+
+// An annotated tag, marking the state of the whole repository
+#[derive(Serialize, Debug)]
+pub(crate) struct Snapshot {
+    name: String,
+    message: String,
+    tagger: Option<String>,
+    #[serde(skip)]
+    timestamp: i64,
+    pub(crate) commit: CommitData,
+}
+
+// Credentials for the remote. They are kept in the .git directory, which neither the API nor git
+// clients can read.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct RemoteCredentials {
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>, // Password or access token, for https remotes
+    pub(crate) ssh_key: Option<String>,  // Private key, for ssh remotes
+}
+
+impl RemoteCredentials {
+    fn is_empty(&self) -> bool {
+        self.username.is_none() && self.password.is_none() && self.ssh_key.is_none()
+    }
+}
+
+// Result of synchronizing with the remote. On conflicts, or changes that are refused, nothing is
+// changed locally or pushed.
+#[derive(Serialize, Debug)]
+pub(crate) enum SyncOutcome {
+    UpToDate,
+    Pushed,
+    FastForwarded,
+    Merged,
+    Conflict(Vec<String>),
+    Refused(String),
+}
+
+pub(crate) enum MergeOutcome {
+    Clean(String),
+    Conflict(Vec<ConflictHunk>),
+}
+
+// Name and email of a user, as stored in the config of their repository
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Profile {
+    pub(crate) name: Option<String>,
+    pub(crate) email: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Identity {
+    pub(crate) name: String,
+    pub(crate) email: String,
+}
+
 pub(crate) struct RepositoryTransaction {
     path: String,
     repo: Repository,
+    author: Option<Identity>,    // Defaults to the committer
+    committer: Option<Identity>, // Defaults to the user configured in git
 }
 
-#[allow(unused)] // TODO: GIT INTEGRATION
-#[allow(dead_code)]
 impl RepositoryTransaction {
     pub(crate) fn from(path: &str) -> Result<Self, Error> {
         match open_repository(path) {
             Ok(r) => Ok(RepositoryTransaction {
                 path: path.to_string(),
                 repo: r,
+                author: None,
+                committer: None,
             }),
             Err(e) => Err(e),
         }
     }
 
+    // Turns the directory at `path` into a repository. Everything in it, and a default .gitignore,
+    // is committed by `committer`.
+    pub(crate) fn init(path: &str, committer: Identity) -> Result<Self, Error> {
+        let repo = Repository::init(path)?;
+        disable_symlinks(&repo)?;
+        let gitignore = Path::new(path).join(".gitignore");
+        if !gitignore.exists() {
+            write(&gitignore, DEFAULT_GITIGNORE)
+                .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        }
+        let mut transaction = RepositoryTransaction {
+            path: path.to_string(),
+            repo,
+            author: None,
+            committer: Some(committer),
+        };
+        transaction.commit_all("Initial commit")?;
+        Ok(transaction)
+    }
+
+    pub(crate) fn set_identities(mut self, author: Identity, committer: Identity) -> Self {
+        self.author = Some(author);
+        self.committer = Some(committer);
+        self
+    }
+
+    // Only the config of the repository itself is read, not the one of the user running the server
+    pub(crate) fn profile(&self) -> Result<Profile, git2::Error> {
+        let config = self.repo.config()?.open_level(ConfigLevel::Local)?;
+        Ok(Profile {
+            name: config.get_string("user.name").ok(),
+            email: config.get_string("user.email").ok(),
+        })
+    }
+
+    pub(crate) fn set_profile(&self, profile: &Profile) -> Result<(), git2::Error> {
+        let mut config = self.repo.config()?.open_level(ConfigLevel::Local)?;
+        for (key, value) in [("user.name", &profile.name), ("user.email", &profile.email)] {
+            match value {
+                Some(v) => config.set_str(key, v)?,
+                None => match config.remove(key) {
+                    Err(e) if e.code() != ErrorCode::NotFound => return Err(e),
+                    _ => (),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remote_url(&self) -> Result<Option<String>, git2::Error> {
+        match self.repo.find_remote(REMOTE_NAME) {
+            Ok(r) => Ok(r.url().map(String::from)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Sets the url of the remote the repository is synced with, or removes it. Credentials for the
+    // previous remote are removed.
+    pub(crate) fn set_remote_url(&self, url: Option<&str>) -> Result<(), git2::Error> {
+        self.set_remote_credentials(&RemoteCredentials::default())?;
+        match (self.remote_url()?, url) {
+            (None, Some(u)) => self.repo.remote(REMOTE_NAME, u).map(|_| ()),
+            (Some(_), Some(u)) => self.repo.remote_set_url(REMOTE_NAME, u),
+            (Some(_), None) => self.repo.remote_delete(REMOTE_NAME),
+            (None, None) => Ok(()),
+        }
+    }
+
+    pub(crate) fn remote_credentials(&self) -> Result<RemoteCredentials, git2::Error> {
+        match read_to_string(self.repo.path().join(CREDENTIALS_FILE)) {
+            Ok(c) => serde_json::from_str(&c).map_err(|e| git2::Error::from_str(&e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RemoteCredentials::default()),
+            Err(e) => Err(git2::Error::from_str(&e.to_string())),
+        }
+    }
+
+    // Without any credentials, the file is removed
+    pub(crate) fn set_remote_credentials(
+        &self,
+        credentials: &RemoteCredentials,
+    ) -> Result<(), git2::Error> {
+        let path = self.repo.path().join(CREDENTIALS_FILE);
+        let result = match credentials.is_empty() {
+            true => remove_file(&path).or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            }),
+            false => serde_json::to_string(credentials)
+                .map_err(io::Error::other)
+                .and_then(|c| write_private(&path, &c)),
+        };
+        result.map_err(|e| git2::Error::from_str(&e.to_string()))
+    }
+
+    // Fetches the current branch from the remote, fast-forwards or merges it, and pushes the result
+    pub(crate) fn sync(&mut self) -> Result<SyncOutcome, git2::Error> {
+        let lock = repository_lock(Path::new(&self.path));
+        let _guard = lock_index(&lock);
+        let head = self.repo.head()?;
+        let branch = head
+            .shorthand()
+            .ok_or_else(|| git2::Error::from_str("HEAD is not a branch"))?
+            .to_string();
+        let local = head.peel_to_commit()?;
+        let mut remote = self.repo.find_remote(REMOTE_NAME)?;
+        let credentials = self.remote_credentials()?;
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(remote_callbacks(&credentials));
+        remote.fetch(&[] as &[&str], Some(&mut options), None)?;
+        let fetched = match self
+            .repo
+            .find_reference(&format!("refs/remotes/{}/{}", REMOTE_NAME, branch))
+        {
+            Ok(r) => Some(r.peel_to_commit()?),
+            Err(e) if e.code() == ErrorCode::NotFound => None, // The remote is empty
+            Err(e) => return Err(e),
+        };
+        let outcome = match fetched {
+            None => SyncOutcome::Pushed,
+            Some(f) if f.id() == local.id() => return Ok(SyncOutcome::UpToDate),
+            Some(f) => {
+                let annotated = self.repo.find_annotated_commit(f.id())?;
+                let (analysis, _) = self.repo.merge_analysis(&[&annotated])?;
+                if analysis.is_up_to_date() {
+                    SyncOutcome::Pushed
+                } else if analysis.is_fast_forward() {
+                    if let Err(reason) = self.check_incoming(&f.tree()?)? {
+                        return Ok(SyncOutcome::Refused(reason));
+                    }
+                    self.checkout(&f)?;
+                    SyncOutcome::FastForwarded
+                } else {
+                    let mut index = self.repo.merge_commits(&local, &f, None)?;
+                    if index.has_conflicts() {
+                        let paths = index
+                            .conflicts()?
+                            .filter_map(|c| c.ok())
+                            .filter_map(|c| c.our.or(c.their).or(c.ancestor))
+                            .map(|e| String::from_utf8_lossy(&e.path).to_string())
+                            .collect();
+                        return Ok(SyncOutcome::Conflict(paths));
+                    }
+                    let oid = index.write_tree_to(&self.repo)?;
+                    let tree = self.repo.find_tree(oid)?;
+                    if let Err(reason) = self.check_incoming(&tree)? {
+                        return Ok(SyncOutcome::Refused(reason));
+                    }
+                    let (author, committer) = self.signatures()?;
+                    let message = format!("Merge {}/{} into {}", REMOTE_NAME, branch, branch);
+                    let merge = self.repo.commit(
+                        None,
+                        &author,
+                        &committer,
+                        &message,
+                        &tree,
+                        &[&local, &f],
+                    )?;
+                    self.checkout(&self.repo.find_commit(merge)?)?;
+                    SyncOutcome::Merged
+                }
+            }
+        };
+        let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch);
+        let mut options = PushOptions::new();
+        options.remote_callbacks(remote_callbacks(&credentials));
+        remote.push(&[refspec.as_str()], Some(&mut options))?;
+        Ok(outcome)
+    }
+
+    // Trees from the remote follow the rules of pushed ones: symbolic links could point anywhere on
+    // the server, and the trash is only changed by the server itself
+    fn check_incoming(&self, tree: &Tree) -> Result<Result<(), String>, git2::Error> {
+        let current = self.repo.head()?.peel_to_tree()?;
+        let trash = |t: &Tree| t.get_name(TRASH_DIR).map(|e| e.id());
+        if trash(tree) != trash(&current) {
+            return Ok(Err("Changes to the trash are not allowed.".to_string()));
+        }
+        let mut symlinks = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.filemode() == i32::from(FileMode::Link) {
+                symlinks.push(format!("{}{}", dir, entry.name().unwrap_or_default()));
+            }
+            TreeWalkResult::Ok
+        })?;
+        match symlinks.is_empty() {
+            true => Ok(Ok(())),
+            false => Ok(Err(format!(
+                "Symbolic links are not allowed: {}",
+                symlinks.join(", ")
+            ))),
+        }
+    }
+
+    // Updates the working directory and moves the current branch to `commit`
+    fn checkout(&self, commit: &Commit) -> Result<(), git2::Error> {
+        self.repo
+            .checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+        self.repo
+            .head()?
+            .set_target(commit.id(), "Sync with remote")
+            .map(|_| ())
+    }
+
     pub(crate) fn find_last_commit(&self) -> Result<CommitData, git2::Error> {
         let obj = self.repo.head()?.resolve()?.peel(ObjectType::Commit)?;
         let commit = obj
@@ -38,33 +365,728 @@ impl RepositoryTransaction {
         Ok(get_commit_data(&commit))
     }
 
+    pub(crate) fn find_commit(&self, oid: Oid) -> Result<CommitData, git2::Error> {
+        let commit = self.repo.find_commit(oid)?;
+        Ok(get_commit_data(&commit))
+    }
+
+    // Commits that changed the file or directory at `path`, newest first. Renames of files are
+    // followed. Skips the first `skip` of them and returns at most `limit`.
+    pub(crate) fn history(
+        &self,
+        path: &Path,
+        skip: usize,
+        limit: usize,
+    ) -> Result<Vec<CommitData>, git2::Error> {
+        // Nothing was committed yet
+        if let Err(e) = self.repo.head() {
+            return match e.code() {
+                ErrorCode::UnbornBranch => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        let mut current = path.to_path_buf();
+        let mut found = 0;
+        let mut commits = Vec::new();
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            let tree = commit.tree()?;
+            let id = match entry_id(&tree, &current) {
+                Some(i) => i,
+                None => continue,
+            };
+            let parent_tree = match commit.parent(0) {
+                Ok(p) => Some(p.tree()?),
+                Err(_) => None,
+            };
+            let parent_id = parent_tree.as_ref().and_then(|t| entry_id(t, &current));
+            if parent_id == Some(id) {
+                continue;
+            }
+            if found >= skip {
+                commits.push(get_commit_data(&commit));
+            }
+            found += 1;
+            if commits.len() >= limit {
+                break;
+            }
+            if let (None, Some(parent_tree)) = (parent_id, parent_tree) {
+                if let Some(old) = self.renamed_from(&parent_tree, &tree, &current)? {
+                    current = old;
+                }
+            }
+        }
+        Ok(commits)
+    }
+
+    // The last commit that changed `path`, if it is among the LAST_CHANGE_DEPTH newest commits.
+    // Unlike `history`, this doesn't walk the whole history up front, so it is cheap enough to do
+    // on every request.
+    pub(crate) fn last_change(&self, path: &Path) -> Result<Option<CommitData>, git2::Error> {
+        if self.repo.head().is_err() {
+            return Ok(None);
+        }
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.set_sorting(Sort::TIME)?;
+        for oid in revwalk.take(LAST_CHANGE_DEPTH) {
+            let commit = self.repo.find_commit(oid?)?;
+            let id = match entry_id(&commit.tree()?, path) {
+                Some(i) => i,
+                None => continue,
+            };
+            let parent_id = match commit.parent(0) {
+                Ok(p) => entry_id(&p.tree()?, path),
+                Err(_) => None,
+            };
+            if parent_id != Some(id) {
+                return Ok(Some(get_commit_data(&commit)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Content of the file at `path` as of the commit `rev` resolves to
+    pub(crate) fn file_at(
+        &self,
+        path: &Path,
+        rev: &str,
+    ) -> Result<(CommitData, Vec<u8>), git2::Error> {
+        let commit = self.repo.revparse_single(rev)?.peel_to_commit()?;
+        let entry = commit.tree()?.get_path(path)?;
+        let blob = self.repo.find_blob(entry.id())?;
+        Ok((get_commit_data(&commit), blob.content().to_vec()))
+    }
+
+    // Names of the entries of the directory at `path` as of the commit `rev` resolves to, and
+    // whether they are directories themselves
+    pub(crate) fn dir_at(
+        &self,
+        path: &Path,
+        rev: &str,
+    ) -> Result<(CommitData, Vec<(String, bool)>), git2::Error> {
+        let commit = self.repo.revparse_single(rev)?.peel_to_commit()?;
+        let tree = commit.tree()?;
+        let id = entry_id(&tree, path).ok_or_else(|| {
+            git2::Error::new(ErrorCode::NotFound, ErrorClass::Tree, "No such path")
+        })?;
+        // A file is no directory to list
+        let tree = self.repo.find_tree(id).map_err(|_| {
+            git2::Error::new(ErrorCode::NotFound, ErrorClass::Tree, "Not a directory")
+        })?;
+        let entries = tree
+            .iter()
+            .map(|e| {
+                let name = String::from_utf8_lossy(e.name_bytes()).to_string();
+                (name, e.kind() == Some(ObjectType::Tree))
+            })
+            .collect();
+        Ok((get_commit_data(&commit), entries))
+    }
+
+    // Annotated tags of the repository, newest first
+    pub(crate) fn snapshots(&self) -> Result<Vec<Snapshot>, git2::Error> {
+        let mut snapshots = Vec::new();
+        for name in self.repo.tag_names(None)?.iter().flatten() {
+            if let Ok(s) = self.snapshot(name) {
+                snapshots.push(s);
+            }
+        }
+        snapshots.sort_by_key(|s| Reverse(s.timestamp));
+        Ok(snapshots)
+    }
+
+    fn snapshot(&self, name: &str) -> Result<Snapshot, git2::Error> {
+        let tag = self
+            .repo
+            .find_reference(&format!("refs/tags/{}", name))?
+            .peel_to_tag()?;
+        let commit = tag.target()?.peel_to_commit()?;
+        let tagger = tag.tagger();
+        Ok(Snapshot {
+            name: name.to_string(),
+            message: tag.message().unwrap_or_default().to_string(),
+            tagger: tagger.as_ref().map(|t| t.to_string()),
+            timestamp: tagger.map_or(0, |t| t.when().seconds()),
+            commit: get_commit_data(&commit),
+        })
+    }
+
+    // Creates an annotated tag `name` of HEAD. Fails if the tag exists.
+    pub(crate) fn create_snapshot(
+        &self,
+        name: &str,
+        message: &str,
+    ) -> Result<Snapshot, git2::Error> {
+        let head = self.repo.head()?.peel(ObjectType::Commit)?;
+        let (tagger, _) = self.signatures()?;
+        self.repo.tag(name, &head, &tagger, message, false)?;
+        self.snapshot(name)
+    }
+
+    pub(crate) fn delete_snapshot(&self, name: &str) -> Result<(), git2::Error> {
+        self.repo.tag_delete(name)
+    }
+
+    // Content of the file at `path` as of `rev`, or in the working directory if `rev` is None.
+    // Files that don't exist (yet) are empty, unless the revision can't be found.
+    pub(crate) fn file_content(
+        &self,
+        path: &Path,
+        rev: Option<&str>,
+    ) -> Result<(Option<CommitData>, String), git2::Error> {
+        let (commit, content) = match rev {
+            Some(r) => match self.file_at(path, r) {
+                Ok((c, content)) => (Some(c), content),
+                Err(e) if e.code() == ErrorCode::NotFound => {
+                    let commit = self.repo.revparse_single(r)?.peel_to_commit()?;
+                    (Some(get_commit_data(&commit)), Vec::new())
+                }
+                Err(e) => return Err(e),
+            },
+            None => (
+                None,
+                read(Path::new(&self.path).join(path)).unwrap_or_default(),
+            ),
+        };
+        Ok((commit, String::from_utf8_lossy(&content).to_string()))
+    }
+
+    // Which commit last changed each line of the committed version of the file
+    pub(crate) fn blame(&self, path: &Path) -> Result<Vec<BlameHunk>, git2::Error> {
+        let blame = self.repo.blame_file(path, None)?;
+        let mut commits: HashMap<Oid, CommitData> = HashMap::new();
+        let mut hunks = Vec::new();
+        for hunk in blame.iter() {
+            let id = hunk.final_commit_id();
+            let commit = match commits.get(&id) {
+                Some(c) => c.clone(),
+                None => {
+                    let c = get_commit_data(&self.repo.find_commit(id)?);
+                    commits.insert(id, c.clone());
+                    c
+                }
+            };
+            hunks.push(BlameHunk {
+                start: hunk.final_start_line(),
+                lines: hunk.lines_in_hunk(),
+                commit,
+            });
+        }
+        Ok(hunks)
+    }
+
+    // Previous path of a file that was renamed to `path` between the two trees
+    fn renamed_from(
+        &self,
+        old: &Tree,
+        new: &Tree,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, git2::Error> {
+        let mut diff = self.repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+        Ok(diff
+            .deltas()
+            .find(|d| d.status() == Delta::Renamed && d.new_file().path() == Some(path))
+            .and_then(|d| d.old_file().path().map(Path::to_path_buf)))
+    }
+
     pub(crate) fn add_and_commit(
         &mut self,
         path: &Path,
         message: &str,
     ) -> Result<Oid, git2::Error> {
+        self.commit_paths(&[path], &[], message)
+    }
+
+    // Stages added or changed files and removed files or directories in a single commit
+    pub(crate) fn commit_paths<P: AsRef<Path>>(
+        &mut self,
+        add: &[P],
+        remove: &[P],
+        message: &str,
+    ) -> Result<Oid, git2::Error> {
+        let lock = repository_lock(Path::new(&self.path));
+        let _guard = lock_index(&lock);
+        let mut index = self.repo.index()?;
+        for path in remove.iter().map(|p| p.as_ref()) {
+            match index.get_path(path, 0) {
+                Some(_) => index.remove_path(path)?,
+                None => index.remove_dir(path, 0)?,
+            }
+        }
+        for path in add.iter().map(|p| p.as_ref()) {
+            index.add_path(path)?;
+        }
+        self.commit_index(&mut index, message)
+    }
+
+    // Commits the current state of `paths`, whether they were changed or removed. Returns None if
+    // nothing changed since the last commit.
+    pub(crate) fn commit_changes<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+        message: &str,
+    ) -> Result<Option<Oid>, git2::Error> {
+        let lock = repository_lock(Path::new(&self.path));
+        let _guard = lock_index(&lock);
+        let mut index = self.repo.index()?;
+        for path in paths.iter().map(|p| p.as_ref()) {
+            if Path::new(&self.path).join(path).is_file() {
+                index.add_path(path)?;
+            } else if index.get_path(path, 0).is_some() {
+                index.remove_path(path)?;
+            }
+        }
+        self.commit_if_changed(&mut index, message)
+    }
+
+    // Commits everything in the working directory that changed, e.g. saves that were still waiting
+    // to be committed when the server stopped. Returns None if nothing changed.
+    pub(crate) fn commit_all(&mut self, message: &str) -> Result<Option<Oid>, git2::Error> {
+        let lock = repository_lock(Path::new(&self.path));
+        let _guard = lock_index(&lock);
         let mut index = self.repo.index()?;
-        index.add_path(path)?;
+        index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)?;
+        index.update_all(["*"].iter(), None)?;
+        self.commit_if_changed(&mut index, message)
+    }
+
+    fn commit_if_changed(
+        &mut self,
+        index: &mut Index,
+        message: &str,
+    ) -> Result<Option<Oid>, git2::Error> {
+        let tree = index.write_tree()?;
+        if let Ok(head) = self.repo.head().and_then(|h| h.peel_to_tree()) {
+            if head.id() == tree {
+                index.write()?;
+                return Ok(None);
+            }
+        }
+        self.commit_index(index, message).map(Some)
+    }
+
+    // Applies a unified diff to a single file in the working directory. Fails without touching the
+    // file if the diff does not apply cleanly.
+    pub(crate) fn apply_patch(&self, path: &Path, patch: &str) -> Result<(), git2::Error> {
+        let hunks = patch
+            .find("@@")
+            .map(|i| &patch[i..])
+            .ok_or_else(|| git2::Error::from_str("Patch contains no hunks"))?;
+        let p = path.to_string_lossy();
+        let patch = format!(
+            "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n{1}",
+            p, hunks
+        );
+        let diff = Diff::from_buffer(patch.as_bytes())?;
+        self.repo.apply(&diff, ApplyLocation::WorkDir, None)
+    }
+
+    // Three-way merge of a single file: `base` is the commit the client started editing from, `ours`
+    // the current content of the file and `theirs` the content the client submitted.
+    pub(crate) fn merge_file(
+        &self,
+        path: &Path,
+        base: &str,
+        ours: &str,
+        theirs: &str,
+    ) -> Result<MergeOutcome, git2::Error> {
+        let base_commit = self.repo.revparse_single(base)?.peel_to_commit()?;
+        let ancestor = match base_commit.tree()?.get_path(path) {
+            Ok(e) => self.repo.find_blob(e.id())?.content().to_vec(),
+            Err(_) => Vec::new(),
+        };
+        let ancestor = String::from_utf8_lossy(&ancestor).to_string();
+        if ancestor == ours {
+            return Ok(MergeOutcome::Clean(theirs.to_string()));
+        }
+        let index = self.repo.merge_trees(
+            &self.single_file_tree(&ancestor)?,
+            &self.single_file_tree(ours)?,
+            &self.single_file_tree(theirs)?,
+            None,
+        )?;
+        if index.has_conflicts() {
+            return Ok(MergeOutcome::Conflict(conflict_hunks(
+                &ancestor, ours, theirs,
+            )?));
+        }
+        let entry = index
+            .get_path(Path::new(MERGE_FILE_NAME), 0)
+            .ok_or_else(|| git2::Error::from_str("Merge result is missing"))?;
+        let blob = self.repo.find_blob(entry.id)?;
+        Ok(MergeOutcome::Clean(
+            String::from_utf8_lossy(blob.content()).to_string(),
+        ))
+    }
+
+    fn single_file_tree(&self, content: &str) -> Result<Tree<'_>, git2::Error> {
+        let blob = self.repo.blob(content.as_bytes())?;
+        let mut builder = self.repo.treebuilder(None)?;
+        builder.insert(MERGE_FILE_NAME, blob, 0o100644)?;
+        let oid = builder.write()?;
+        self.repo.find_tree(oid)
+    }
+
+    fn signatures(&self) -> Result<(Signature<'static>, Signature<'static>), git2::Error> {
+        let committer = match &self.committer {
+            Some(c) => Signature::now(&c.name, &c.email)?,
+            None => self.repo.signature()?,
+        };
+        let author = match &self.author {
+            Some(a) => Signature::now(&a.name, &a.email)?,
+            None => committer.to_owned(),
+        };
+        Ok((author, committer))
+    }
+
+    fn commit_index(&mut self, index: &mut Index, message: &str) -> Result<Oid, git2::Error> {
+        index.write()?;
         let oid = index.write_tree()?;
-        let signature = Signature::now("Zbigniew Siciarz", "zbigniew@siciarz.net")?; // TODO: User Data
-        let obj = self.repo.head()?.resolve()?.peel(ObjectType::Commit)?;
-        let parent_commit = obj
-            .into_commit()
-            .map_err(|_| git2::Error::from_str("Couldn't find commit"))?;
+        let (author, committer) = self.signatures()?;
+        // The first commit of a repository has no parent
+        let parent_commit = match self.repo.head() {
+            Ok(h) => Some(
+                h.resolve()?
+                    .peel(ObjectType::Commit)?
+                    .into_commit()
+                    .map_err(|_| git2::Error::from_str("Couldn't find commit"))?,
+            ),
+            Err(e) if e.code() == ErrorCode::UnbornBranch => None,
+            Err(e) => return Err(e),
+        };
+        let parents: Vec<&Commit> = parent_commit.iter().collect();
         let tree = self.repo.find_tree(oid)?;
         self.repo.commit(
-            Some("HEAD"),      //  point HEAD to our new commit
-            &signature,        // author
-            &signature,        // committer
-            message,           // commit message
-            &tree,             // tree
-            &[&parent_commit], // parents
+            Some("HEAD"), //  point HEAD to our new commit
+            &author,      // author
+            &committer,   // committer
+            message,      // commit message
+            &tree,        // tree
+            &parents,     // parents
         )
     }
 }
 
+const MERGE_FILE_NAME: &str = "merge.md";
+const REMOTE_NAME: &str = "origin";
+const CREDENTIALS_FILE: &str = "zk_remote_credentials.json";
+const LAST_CHANGE_DEPTH: usize = 1000;
+const DEFAULT_GITIGNORE: &str = "# Files of editors and operating systems
+.DS_Store
+Thumbs.db
+*.swp
+*~
+";
+
+// Line and word level hunks of the changes from `old` to `new`, and the same as a unified diff
+pub(crate) fn diff_hunks(
+    path: &Path,
+    old: &str,
+    new: &str,
+) -> Result<(Vec<DiffHunk>, String), git2::Error> {
+    let mut patch =
+        Patch::from_buffers(old.as_bytes(), Some(path), new.as_bytes(), Some(path), None)?;
+    let mut hunks = Vec::new();
+    for h in 0..patch.num_hunks() {
+        let (hunk, n) = patch.hunk(h)?;
+        let mut lines = Vec::new();
+        let (mut old_text, mut new_text) = (String::new(), String::new());
+        for l in 0..n {
+            let line = patch.line_in_hunk(h, l)?;
+            let content = String::from_utf8_lossy(line.content()).to_string();
+            match line.origin() {
+                '-' => old_text.push_str(&content),
+                '+' => new_text.push_str(&content),
+                ' ' => {
+                    old_text.push_str(&content);
+                    new_text.push_str(&content);
+                }
+                _ => continue, // "No newline at end of file" markers
+            }
+            lines.push(DiffLine {
+                origin: line.origin(),
+                old_lineno: line.old_lineno(),
+                new_lineno: line.new_lineno(),
+                content,
+            });
+        }
+        hunks.push(DiffHunk {
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            lines,
+            words: diff_words(&old_text, &new_text)?,
+        });
+    }
+    let unified = String::from_utf8_lossy(&patch.to_buf()?).to_string();
+    Ok((hunks, unified))
+}
+
+// Diffs two texts word by word. Every word (or run of whitespace or punctuation) is replaced by
+// a line containing its number, so the line based diff can be used.
+fn diff_words(old: &str, new: &str) -> Result<Vec<DiffWord>, git2::Error> {
+    let old_words = split_words(old);
+    let new_words = split_words(new);
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    let old_ids = encode_words(&old_words, &mut ids);
+    let new_ids = encode_words(&new_words, &mut ids);
+    let mut opts = DiffOptions::new();
+    opts.context_lines(u32::MAX / 2);
+    let patch = Patch::from_buffers(
+        old_ids.as_bytes(),
+        None,
+        new_ids.as_bytes(),
+        None,
+        Some(&mut opts),
+    )?;
+    let mut words: Vec<DiffWord> = Vec::new();
+    for h in 0..patch.num_hunks() {
+        for l in 0..patch.num_lines_in_hunk(h)? {
+            let line = patch.line_in_hunk(h, l)?;
+            let word = match (line.origin(), line.old_lineno(), line.new_lineno()) {
+                ('-', Some(n), _) | (' ', Some(n), _) => old_words[n as usize - 1],
+                ('+', _, Some(n)) => new_words[n as usize - 1],
+                _ => continue,
+            };
+            match words.last_mut() {
+                Some(w) if w.origin == line.origin() => w.content.push_str(word),
+                _ => words.push(DiffWord {
+                    origin: line.origin(),
+                    content: word.to_string(),
+                }),
+            }
+        }
+    }
+    // Without changes, there are no hunks at all
+    if words.is_empty() && !old.is_empty() {
+        words.push(DiffWord {
+            origin: ' ',
+            content: old.to_string(),
+        });
+    }
+    Ok(words)
+}
+
+fn encode_words<'a>(words: &[&'a str], ids: &mut HashMap<&'a str, usize>) -> String {
+    words
+        .iter()
+        .map(|w| {
+            let next = ids.len();
+            format!("{}\n", ids.entry(w).or_insert(next))
+        })
+        .collect()
+}
+
+// Splits a text into runs of alphanumeric characters, runs of whitespace and single other characters
+fn split_words(text: &str) -> Vec<&str> {
+    let class = |c: char| match c {
+        c if c.is_alphanumeric() => 0,
+        c if c.is_whitespace() => 1,
+        _ => 2,
+    };
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut last: Option<u8> = None;
+    for (i, c) in text.char_indices() {
+        let current = class(c);
+        if last.is_some_and(|l| l != current || current == 2) {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        last = Some(current);
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+// Changes of one side against the base: (first changed line, number of changed lines, new content)
+fn changed_blocks(base: &str, side: &str) -> Result<Vec<(usize, usize, String)>, git2::Error> {
+    let mut opts = DiffOptions::new();
+    opts.context_lines(0);
+    let patch = Patch::from_buffers(
+        base.as_bytes(),
+        None,
+        side.as_bytes(),
+        None,
+        Some(&mut opts),
+    )?;
+    let mut blocks = Vec::new();
+    for h in 0..patch.num_hunks() {
+        let (hunk, lines) = patch.hunk(h)?;
+        // Pure insertions report the line after which they are inserted
+        let start = match hunk.old_lines() {
+            0 => hunk.old_start() as usize,
+            _ => hunk.old_start() as usize - 1,
+        };
+        let mut content = String::new();
+        for l in 0..lines {
+            let line = patch.line_in_hunk(h, l)?;
+            if line.origin() == '+' {
+                content.push_str(&String::from_utf8_lossy(line.content()));
+            }
+        }
+        blocks.push((start, hunk.old_lines() as usize, content));
+    }
+    Ok(blocks)
+}
+
+// Builds the content of the base range [start, end) with the changes of one side applied
+fn apply_blocks(
+    base: &[&str],
+    start: usize,
+    end: usize,
+    blocks: &[(usize, usize, String)],
+) -> String {
+    let mut result = String::new();
+    let mut i = start;
+    for (s, n, content) in blocks.iter().filter(|b| b.0 >= start && b.0 + b.1 <= end) {
+        result.push_str(&base[i..*s].concat());
+        result.push_str(content);
+        i = s + n;
+    }
+    result.push_str(&base[i..end].concat());
+    result
+}
+
+fn conflict_hunks(base: &str, ours: &str, theirs: &str) -> Result<Vec<ConflictHunk>, git2::Error> {
+    let ours_blocks = changed_blocks(base, ours)?;
+    let theirs_blocks = changed_blocks(base, theirs)?;
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    // Group all changed blocks into clusters of touching or overlapping ranges of the base
+    let mut ranges: Vec<(usize, usize, bool, bool)> = ours_blocks
+        .iter()
+        .map(|b| (b.0, b.0 + b.1, true, false))
+        .chain(theirs_blocks.iter().map(|b| (b.0, b.0 + b.1, false, true)))
+        .collect();
+    ranges.sort();
+    let mut clusters: Vec<(usize, usize, bool, bool)> = Vec::new();
+    for r in ranges {
+        match clusters.last_mut() {
+            Some(c) if r.0 <= c.1 => {
+                c.1 = c.1.max(r.1);
+                c.2 |= r.2;
+                c.3 |= r.3;
+            }
+            _ => clusters.push(r),
+        }
+    }
+    let mut hunks: Vec<ConflictHunk> = clusters
+        .into_iter()
+        .filter(|c| c.2 && c.3)
+        .map(|(start, end, _, _)| ConflictHunk {
+            base_start: start + 1,
+            base: base_lines[start..end].concat(),
+            ours: apply_blocks(&base_lines, start, end, &ours_blocks),
+            theirs: apply_blocks(&base_lines, start, end, &theirs_blocks),
+        })
+        .collect();
+    // Git may consider changes as conflicting that are not adjacent line-wise
+    if hunks.is_empty() {
+        hunks.push(ConflictHunk {
+            base_start: 1,
+            base: base.to_string(),
+            ours: ours.to_string(),
+            theirs: theirs.to_string(),
+        });
+    }
+    Ok(hunks)
+}
+
+// Id of the blob or tree at `path`. The empty path refers to the tree itself.
+fn entry_id(tree: &Tree, path: &Path) -> Option<Oid> {
+    if path.components().all(|c| c == Component::CurDir) {
+        return Some(tree.id());
+    }
+    tree.get_path(path).ok().map(|e| e.id())
+}
+
+// Only the credentials the user stored for the remote are used, never the ssh agent or credential
+// helpers of the user running the server. Refs the remote refuses to update make the push fail.
+fn remote_callbacks(credentials: &RemoteCredentials) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut tried = CredentialType::empty();
+    callbacks.credentials(move |_, username, allowed| {
+        // Called again as long as authentication fails, so every method is only tried once
+        let method = [
+            CredentialType::USERNAME,
+            CredentialType::SSH_MEMORY,
+            CredentialType::USER_PASS_PLAINTEXT,
+        ]
+        .iter()
+        .copied()
+        .find(|m| allowed.contains(*m) && !tried.contains(*m));
+        let username = credentials
+            .username
+            .as_deref()
+            .or(username)
+            .unwrap_or("git");
+        let no_credentials = || git2::Error::from_str("No valid credentials for the remote");
+        if let Some(m) = method {
+            tried.insert(m);
+        }
+        match (method, &credentials.ssh_key, &credentials.password) {
+            (Some(CredentialType::USERNAME), _, _) => Cred::username(username),
+            (Some(CredentialType::SSH_MEMORY), Some(key), _) => {
+                Cred::ssh_key_from_memory(username, None, key, None)
+            }
+            (Some(CredentialType::USER_PASS_PLAINTEXT), _, Some(password)) => {
+                Cred::userpass_plaintext(username, password)
+            }
+            _ => Err(no_credentials()),
+        }
+    });
+    callbacks.push_update_reference(|reference, status| match status {
+        Some(s) => Err(git2::Error::from_str(&format!(
+            "The remote rejected {}: {}",
+            reference, s
+        ))),
+        None => Ok(()),
+    });
+    callbacks
+}
+
+// Index writes are serialized per repository, as requests, batched commits and pushes may change
+// the same repository at once
+pub(crate) fn repository_lock(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = lock_index(LOCKS.get_or_init(Default::default));
+    locks.entry(path.to_path_buf()).or_default().clone()
+}
+
+// Held from checking the If-Match header of a request until its changes are written, so concurrent
+// writers can't both pass the precondition. The repository lock is taken while it is held.
+pub(crate) fn precondition_lock(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = lock_index(LOCKS.get_or_init(Default::default));
+    locks.entry(path.to_path_buf()).or_default().clone()
+}
+
+// A panic while the lock was held doesn't leave the index in a worse state than a crash would
+pub(crate) fn lock_index<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 fn open_repository(path: &str) -> Result<Repository, Error> {
-    Repository::open(path)
+    let repo = Repository::open(path)?;
+    disable_symlinks(&repo)?;
+    Ok(repo)
+}
+
+// Symbolic links from pushes or syncs are checked out as plain files containing the target
+fn disable_symlinks(repo: &Repository) -> Result<(), Error> {
+    let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+    match config.get_bool("core.symlinks") {
+        Ok(false) => Ok(()),
+        _ => config.set_bool("core.symlinks", false),
+    }
 }
 
 fn get_commit_data(commit: &Commit) -> CommitData {
@@ -79,3 +1101,181 @@ fn get_commit_data(commit: &Commit) -> CommitData {
         message: commit.message().unwrap_or("no commit message").to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Identity;
+    use super::MergeOutcome;
+    use super::RepositoryTransaction;
+    use git2::Oid;
+    use git2::Repository;
+    use git2::Signature;
+    use std::env;
+    use std::fs::remove_dir_all;
+    use std::fs::symlink_metadata;
+    use std::fs::write;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            name: name.to_string(),
+            email: format!("{}@localhost", name),
+        }
+    }
+
+    fn edit(repo: &mut RepositoryTransaction, dir: &Path, file: &str, content: &str) {
+        write(dir.join(file), content).unwrap();
+        repo.add_and_commit(Path::new(file), &format!("Update {}", file))
+            .unwrap();
+    }
+
+    fn outcome(repo: &mut RepositoryTransaction) -> String {
+        format!("{:?}", repo.sync().unwrap())
+    }
+
+    // Two repositories synced through a local bare repository
+    #[test]
+    fn sync_with_local_bare_remote() {
+        let base: PathBuf = env::temp_dir().join(format!("zk-sync-{}", std::process::id()));
+        let (remote, a, b) = (base.join("remote.git"), base.join("a"), base.join("b"));
+        Repository::init_bare(&remote).unwrap();
+        std::fs::create_dir_all(&a).unwrap();
+        let mut repo_a = RepositoryTransaction::init(a.to_str().unwrap(), identity("a")).unwrap();
+        repo_a.set_remote_url(remote.to_str()).unwrap();
+        assert_eq!(outcome(&mut repo_a), "Pushed");
+        assert_eq!(outcome(&mut repo_a), "UpToDate");
+
+        Repository::clone(remote.to_str().unwrap(), &b).unwrap();
+        let mut repo_b = RepositoryTransaction::from(b.to_str().unwrap())
+            .unwrap()
+            .set_identities(identity("b"), identity("b"));
+
+        edit(&mut repo_a, &a, "note.md", "one\n");
+        assert_eq!(outcome(&mut repo_a), "Pushed");
+        assert_eq!(outcome(&mut repo_b), "FastForwarded");
+        assert_eq!(std::fs::read_to_string(b.join("note.md")).unwrap(), "one\n");
+
+        edit(&mut repo_a, &a, "a.md", "a\n");
+        edit(&mut repo_b, &b, "b.md", "b\n");
+        assert_eq!(outcome(&mut repo_a), "Pushed");
+        assert_eq!(outcome(&mut repo_b), "Merged");
+        assert_eq!(outcome(&mut repo_a), "FastForwarded");
+        assert!(a.join("b.md").exists());
+
+        edit(&mut repo_a, &a, "note.md", "two\n");
+        edit(&mut repo_b, &b, "note.md", "three\n");
+        assert_eq!(outcome(&mut repo_a), "Pushed");
+        assert_eq!(outcome(&mut repo_b), "Conflict([\"note.md\"])");
+        assert_eq!(
+            std::fs::read_to_string(b.join("note.md")).unwrap(),
+            "three\n"
+        );
+
+        remove_dir_all(&base).ok();
+    }
+
+    // A repository with note.md committed with `content`, and the id of that commit
+    fn merge_base(name: &str, content: &str) -> (PathBuf, RepositoryTransaction, String) {
+        let dir = env::temp_dir().join(format!("zk-merge-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut repo = RepositoryTransaction::init(dir.to_str().unwrap(), identity("a")).unwrap();
+        edit(&mut repo, &dir, "note.md", content);
+        let base = repo.find_last_commit().unwrap().id;
+        (dir, repo, base)
+    }
+
+    fn merge(repo: &RepositoryTransaction, base: &str, ours: &str, theirs: &str) -> MergeOutcome {
+        repo.merge_file(Path::new("note.md"), base, ours, theirs)
+            .unwrap()
+    }
+
+    fn conflicts(outcome: MergeOutcome) -> Vec<(usize, String, String, String)> {
+        match outcome {
+            MergeOutcome::Clean(c) => panic!("Merged cleanly: {:?}", c),
+            MergeOutcome::Conflict(hunks) => hunks
+                .into_iter()
+                .map(|h| (h.base_start, h.base, h.ours, h.theirs))
+                .collect(),
+        }
+    }
+
+    fn hunk(start: usize, base: &str, ours: &str, theirs: &str) -> (usize, String, String, String) {
+        (start, base.into(), ours.into(), theirs.into())
+    }
+
+    #[test]
+    fn merge_clean() {
+        let (dir, repo, base) = merge_base("clean", "a\nb\nc\nd\ne\n");
+        let merged = merge(&repo, &base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n");
+        assert!(matches!(merged, MergeOutcome::Clean(c) if c == "A\nb\nc\nd\nE\n"));
+        // Nothing changed since the base, the submitted content is taken as is
+        let merged = merge(&repo, &base, "a\nb\nc\nd\ne\n", "x\n");
+        assert!(matches!(merged, MergeOutcome::Clean(c) if c == "x\n"));
+        remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let (dir, repo, base) = merge_base("conflict", "a\nb\nc\nd\ne\n");
+        let merged = merge(&repo, &base, "a\nX\nc\nd\nE\n", "a\nY\nc\nd\ne\n");
+        assert_eq!(conflicts(merged), vec![hunk(2, "b\n", "X\n", "Y\n")]);
+        remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn merge_conflict_at_end_of_file() {
+        let (dir, repo, base) = merge_base("end", "a\nb\n");
+        let merged = merge(&repo, &base, "a\nb\nX\n", "a\nb\nY\n");
+        assert_eq!(conflicts(merged), vec![hunk(3, "", "X\n", "Y\n")]);
+        let merged = merge(&repo, &base, "a\nX", "a\nY");
+        assert_eq!(conflicts(merged), vec![hunk(2, "b\n", "X", "Y")]);
+        remove_dir_all(&dir).ok();
+    }
+
+    // Commits `oid` as `name` to the branch of the bare repository, on top of `parent`
+    fn commit_to_remote(remote: &Repository, parent: Oid, name: &str, oid: Oid, mode: i32) {
+        let parent = remote.find_commit(parent).unwrap();
+        let mut builder = remote.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        builder.insert(name, oid, mode).unwrap();
+        let tree = remote.find_tree(builder.write().unwrap()).unwrap();
+        let signature = Signature::now("remote", "remote@localhost").unwrap();
+        let branch = remote.head().unwrap().name().unwrap().to_string();
+        remote
+            .commit(None, &signature, &signature, "Remote", &tree, &[&parent])
+            .and_then(|c| remote.reference(&branch, c, true, "Remote"))
+            .unwrap();
+    }
+
+    // Symbolic links and changes to the trash are neither fast-forwarded nor merged
+    #[test]
+    fn sync_refuses_symlinks_and_trash() {
+        let base: PathBuf = env::temp_dir().join(format!("zk-refuse-{}", std::process::id()));
+        let (remote, a) = (base.join("remote.git"), base.join("a"));
+        let bare = Repository::init_bare(&remote).unwrap();
+        std::fs::create_dir_all(&a).unwrap();
+        let mut repo_a = RepositoryTransaction::init(a.to_str().unwrap(), identity("a")).unwrap();
+        repo_a.set_remote_url(remote.to_str()).unwrap();
+        assert_eq!(outcome(&mut repo_a), "Pushed");
+        let start = bare.head().unwrap().target().unwrap();
+
+        let link = bare.blob(b"/etc/passwd").unwrap();
+        commit_to_remote(&bare, start, "leak.md", link, 0o120000);
+        let refused = "Refused(\"Symbolic links are not allowed: leak.md\")";
+        assert_eq!(outcome(&mut repo_a), refused);
+        assert!(symlink_metadata(a.join("leak.md")).is_err());
+        edit(&mut repo_a, &a, "note.md", "one\n");
+        assert_eq!(outcome(&mut repo_a), refused);
+        assert!(symlink_metadata(a.join("leak.md")).is_err());
+
+        let index = bare.blob(b"[]").unwrap();
+        let mut trash = bare.treebuilder(None).unwrap();
+        trash.insert("index.json", index, 0o100644).unwrap();
+        commit_to_remote(&bare, start, ".trash", trash.write().unwrap(), 0o040000);
+        let refused = "Refused(\"Changes to the trash are not allowed.\")";
+        assert_eq!(outcome(&mut repo_a), refused);
+        assert!(!a.join(".trash").exists());
+
+        remove_dir_all(&base).ok();
+    }
+}
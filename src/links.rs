@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Rewrites [[wiki links]] and relative [markdown](links.md) of a note that (possibly) moved from
+// `old` to `new`, after the notes in `moves` (old path -> new path, relative to the users
+// directory) have been moved. Returns None if nothing changed.
+pub(crate) fn rewrite_links(
+    content: &str,
+    old: &Path,
+    new: &Path,
+    moves: &HashMap<PathBuf, PathBuf>,
+) -> Option<String> {
+    let content_new = rewrite_wiki_links(content, moves);
+    let content_new = rewrite_markdown_links(&content_new, old, new, moves);
+    if content_new == content {
+        None
+    } else {
+        Some(content_new)
+    }
+}
+
+// Wiki links are either relative to the users directory or just the name of a note
+fn rewrite_wiki_links(content: &str, moves: &HashMap<PathBuf, PathBuf>) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("[[") {
+        let (before, after) = rest.split_at(start + 2);
+        result.push_str(before);
+        let end = match after.find("]]") {
+            Some(e) if !after[..e].contains('\n') => e,
+            _ => {
+                rest = after;
+                continue;
+            }
+        };
+        let inner = &after[..end];
+        let (target, alias) = match inner.find('|') {
+            Some(i) => inner.split_at(i),
+            None => (inner, ""),
+        };
+        let (target, anchor) = match target.find('#') {
+            Some(i) => target.split_at(i),
+            None => (target, ""),
+        };
+        match rewrite_wiki_target(target, moves) {
+            Some(t) => {
+                result.push_str(&t);
+                result.push_str(anchor);
+                result.push_str(alias);
+            }
+            None => result.push_str(inner),
+        }
+        rest = &after[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn rewrite_wiki_target(target: &str, moves: &HashMap<PathBuf, PathBuf>) -> Option<String> {
+    let trimmed = target.trim();
+    let with_extension = trimmed.ends_with(".md");
+    let path = match with_extension {
+        true => PathBuf::from(trimmed.trim_start_matches('/')),
+        false => PathBuf::from(format!("{}.md", trimmed.trim_start_matches('/'))),
+    };
+    let path = normalize(&path)?;
+    let new = match moves.get(&path) {
+        Some(n) => n,
+        None if path.components().count() == 1 => {
+            // Link by name only: find a moved note with that name
+            let (_, new) = moves
+                .iter()
+                .find(|(o, _)| o.file_name() == path.file_name())?;
+            if new.file_name() == path.file_name() {
+                return None;
+            }
+            return Some(file_link_name(new, with_extension));
+        }
+        None => return None,
+    };
+    let mut link = new.to_string_lossy().to_string();
+    if !with_extension {
+        link.truncate(link.len() - ".md".len());
+    }
+    Some(link)
+}
+
+fn file_link_name(path: &Path, with_extension: bool) -> String {
+    match with_extension {
+        true => path.file_name(),
+        false => path.file_stem(),
+    }
+    .unwrap_or_default()
+    .to_string_lossy()
+    .to_string()
+}
+
+// Markdown links are relative to the directory of the note, or to the users directory if they start
+// with a slash
+fn rewrite_markdown_links(
+    content: &str,
+    old: &Path,
+    new: &Path,
+    moves: &HashMap<PathBuf, PathBuf>,
+) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("](") {
+        let (before, after) = rest.split_at(start + 2);
+        result.push_str(before);
+        let end = match after.find(')') {
+            Some(e) if !after[..e].contains('\n') => e,
+            _ => {
+                rest = after;
+                continue;
+            }
+        };
+        let target = &after[..end];
+        match rewrite_markdown_target(target, old, new, moves) {
+            Some(t) => result.push_str(&t),
+            None => result.push_str(target),
+        }
+        rest = &after[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn rewrite_markdown_target(
+    target: &str,
+    old: &Path,
+    new: &Path,
+    moves: &HashMap<PathBuf, PathBuf>,
+) -> Option<String> {
+    // Links with a scheme (https:, mailto:, data:, ...) point outside of the repository
+    if target.is_empty() || target.starts_with('#') || has_scheme(target) {
+        return None;
+    }
+    // Anything after the path (an anchor or a title) is kept as is
+    let (link, anchor) = match target.find(['#', ' ']) {
+        Some(i) => target.split_at(i),
+        None => (target, ""),
+    };
+    // Encoded links stay encoded
+    let encoded = link.contains('%');
+    let encode = |p: &Path| match encoded {
+        true => percent_encode(&p.to_string_lossy()),
+        false => p.to_string_lossy().to_string(),
+    };
+    let link = percent_decode(link)?;
+    if link.starts_with('/') {
+        let resolved = normalize(Path::new(link.trim_start_matches('/')))?;
+        let moved = moves.get(&resolved)?;
+        return Some(format!("/{}{}", encode(moved), anchor));
+    }
+    let old_dir = old.parent().unwrap_or_else(|| Path::new(""));
+    let new_dir = new.parent().unwrap_or_else(|| Path::new(""));
+    let resolved = normalize(&old_dir.join(&link))?;
+    if old == new && !moves.contains_key(&resolved) {
+        return None;
+    }
+    let moved = moves.get(&resolved).unwrap_or(&resolved);
+    let relative = relative_path(new_dir, moved);
+    if relative == Path::new(&link) {
+        return None;
+    }
+    Some(format!("{}{}", encode(&relative), anchor))
+}
+
+// Whether the target starts with a URI scheme, like `mailto:`
+fn has_scheme(target: &str) -> bool {
+    match target.find(':') {
+        Some(i) => {
+            let scheme = &target[..i];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+        }
+        None => false,
+    }
+}
+
+// Decodes %xx sequences. Returns None if they don't decode to UTF-8.
+fn percent_decode(link: &str) -> Option<String> {
+    let bytes = link.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex.map(|h| u8::from_str_radix(h, 16)) {
+            Some(Ok(b)) if bytes[i] == b'%' => {
+                result.push(b);
+                i += 3;
+            }
+            _ => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+// Encodes everything but unreserved characters and slashes
+fn percent_encode(link: &str) -> String {
+    link.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Resolves `.` and `..` without touching the filesystem. Returns None for paths outside of the
+// users directory.
+pub(crate) fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(n) => result.push(n),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !result.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(result)
+}
+
+fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir.components().collect();
+    let to_components: Vec<Component> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(to_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut result = PathBuf::new();
+    for _ in common..from.len() {
+        result.push("..");
+    }
+    for c in &to_components[common..] {
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rewrite_markdown_target;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    fn moves() -> HashMap<PathBuf, PathBuf> {
+        let mut moves = HashMap::new();
+        moves.insert(PathBuf::from("a/old.md"), PathBuf::from("b/new.md"));
+        moves.insert(PathBuf::from("a/my note.md"), PathBuf::from("b/my note.md"));
+        moves
+    }
+
+    fn rewrite(target: &str, old: &str, new: &str) -> Option<String> {
+        rewrite_markdown_target(target, Path::new(old), Path::new(new), &moves())
+    }
+
+    #[test]
+    fn relative_link_to_moved_note() {
+        assert_eq!(
+            rewrite("old.md", "a/x.md", "a/x.md"),
+            Some("../b/new.md".into())
+        );
+        assert_eq!(
+            rewrite("old.md#heading", "a/x.md", "a/x.md"),
+            Some("../b/new.md#heading".into())
+        );
+    }
+
+    #[test]
+    fn link_from_moved_note() {
+        assert_eq!(
+            rewrite("x.md", "a/old.md", "b/new.md"),
+            Some("../a/x.md".into())
+        );
+        assert_eq!(rewrite("/a/x.md", "a/old.md", "b/new.md"), None);
+    }
+
+    #[test]
+    fn absolute_link() {
+        assert_eq!(
+            rewrite("/a/old.md", "x.md", "x.md"),
+            Some("/b/new.md".into())
+        );
+        assert_eq!(rewrite("/a/other.md", "x.md", "x.md"), None);
+    }
+
+    #[test]
+    fn links_with_scheme_are_kept() {
+        for target in [
+            "https://example.org/a/old.md",
+            "mailto:someone@example.org",
+            "tel:+123456",
+            "data:text/plain,old.md",
+            "git+ssh:old.md",
+        ] {
+            assert_eq!(rewrite(target, "a/old.md", "b/new.md"), None, "{}", target);
+        }
+    }
+
+    #[test]
+    fn anchors_and_empty_links_are_kept() {
+        assert_eq!(rewrite("", "a/old.md", "b/new.md"), None);
+        assert_eq!(rewrite("#heading", "a/old.md", "b/new.md"), None);
+    }
+
+    #[test]
+    fn percent_encoded_link() {
+        assert_eq!(
+            rewrite("my%20note.md", "a/x.md", "a/x.md"),
+            Some("../b/my%20note.md".into())
+        );
+        assert_eq!(rewrite("%FF.md", "a/x.md", "a/x.md"), None);
+    }
+
+    #[test]
+    fn links_outside_the_repository_are_kept() {
+        assert_eq!(rewrite("../../old.md", "a/x.md", "b/x.md"), None);
+    }
+}
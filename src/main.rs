@@ -4,16 +4,11 @@ extern crate rocket;
 extern crate serde_derive;
 
 use crate::state::ZKConfig;
-use crypto_hashes::sha2::Sha256;
 use figment::providers::Env;
 use figment::providers::Format;
 use figment::providers::Toml;
 use figment::Figment;
-use hmac::Hmac;
-use hmac::Mac;
-use hmac::NewMac;
-use rand;
-use rand::Rng;
+use rocket::config::SecretKey;
 use rocket::fairing::AdHoc;
 use rocket::Build;
 mod deserializables;
@@ -21,11 +16,13 @@ mod fairings;
 mod filesystem_interact;
 mod functions;
 mod git_interact;
+mod links;
 mod requestguards;
 mod responders;
 mod routes_catchers;
 mod routes_delete;
 mod routes_get;
+mod routes_git;
 mod routes_options;
 mod routes_patch;
 mod routes_post;
@@ -34,6 +31,8 @@ mod routes_static_get;
 mod serializables;
 mod state;
 mod tokens;
+mod trash;
+mod users;
 
 #[launch]
 fn rocket() -> _ {
@@ -41,7 +40,7 @@ fn rocket() -> _ {
         .attach(AdHoc::on_ignite("Parse options", |rocket| {
             Box::pin(async move { parse_options(rocket) })
         }))
-        .manage(state::ApiKey(generate_hmac().finalize()))
+        .manage(state::PendingCommits::default())
         .register("/", catchers![routes_catchers::not_found])
         .attach(fairings::Gzip)
         .attach(fairings::Caching)
@@ -57,11 +56,6 @@ fn read_config() -> ZKConfig {
     config
 }
 
-fn generate_hmac() -> Hmac<Sha256> {
-    return Hmac::new_from_slice(&rand::thread_rng().gen::<[u8; 32]>())
-        .expect("Failed to generate Secret. Aborting.");
-}
-
 fn parse_options(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
     let config = read_config();
     let rocket = match config.cors {
@@ -84,14 +78,83 @@ fn parse_options(rocket: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
             routes![routes_static_get::app, routes_static_get::static_or_app,],
         ),
     };
+    let rocket = match config.sync_interval {
+        Some(0) => panic!("sync_interval must be at least one second. Aborting."),
+        Some(interval) => rocket.attach(fairings::RemoteSync { interval }),
+        None => rocket,
+    };
+    let rocket = rocket.attach(fairings::LeftoverCommits);
+    let rocket = match config.commit_delay {
+        Some(delay) => rocket.attach(fairings::CommitBatching { delay }),
+        None => rocket,
+    };
     let rocket = rocket.mount(
         config.path.as_str(), // TODO: Allow setting this in ZK.toml
         routes![
             routes_get::api,
             routes_get::api_index,
+            routes_get::raw,
+            routes_get::history,
+            routes_get::history_index,
+            routes_get::revision,
+            routes_get::revision_index,
+            routes_get::diff,
+            routes_get::blame,
+            routes_get::profile,
+            routes_get::remote,
+            routes_get::list_trash,
+            routes_get::snapshots,
+            routes_get::users,
+            routes_get::keys,
             routes_post::auth,
-            routes_post::auth_index
+            routes_post::auth_index,
+            routes_post::create,
+            routes_post::create_index,
+            routes_post::move_entry,
+            routes_post::restore,
+            routes_post::revert,
+            routes_post::sync,
+            routes_post::commit,
+            routes_post::snapshot,
+            routes_post::create_user,
+            routes_post::rotate_keys,
+            routes_post::refresh,
+            routes_post::logout,
+            routes_post::upload,
+            routes_post::upload_index,
+            routes_put::save,
+            routes_put::profile,
+            routes_put::remote,
+            routes_put::user,
+            routes_delete::delete,
+            routes_delete::purge_trash,
+            routes_delete::snapshot,
+            routes_delete::user,
+            routes_delete::retire_key,
+            routes_delete::sessions,
+            routes_patch::patch,
+            routes_git::info_refs,
+            routes_git::service
         ],
     );
-    rocket.manage(config)
+    let users =
+        users::UserStore::open(&config).expect("Failed to open the user accounts. Aborting.");
+    let key = state::ApiKey::open(&config).expect("Failed to open the signing keys. Aborting.");
+    // Sessions survive restarts
+    let configured = rocket.figment().extract_inner::<SecretKey>("secret_key");
+    let rocket = match configured.is_ok_and(|k| k.is_provided()) {
+        true => rocket,
+        false => {
+            let key = state::cookie_key(&config).expect("Failed to open the cookie key. Aborting.");
+            let figment = rocket.figment().clone().merge(("secret_key", key));
+            rocket.configure(figment)
+        }
+    };
+    let revocations =
+        state::Revocations::open(&config).expect("Failed to open the revoked tokens. Aborting.");
+    rocket
+        .manage(config)
+        .manage(users)
+        .manage(key)
+        .manage(revocations)
 }
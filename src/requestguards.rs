@@ -1,7 +1,10 @@
+use crate::filesystem_interact::Entry;
 use crate::serializables::Claims;
 use crate::state::ApiKey;
+use crate::state::Revocations;
 use crate::state::ZKConfig;
 use crate::tokens::validate_token;
+use crate::users::UserStore;
 use jsonwebtoken::errors::Error;
 use rocket::http::uri::error::PathError;
 use rocket::http::uri::fmt::Path;
@@ -20,6 +23,8 @@ pub(crate) enum AuthError {
     WrongUsernamePassword,
     UsernameInvalidated,
     PathTraversalAttempt,
+    NotAdmin,
+    Revoked,
     CSRFError(Error),
     JWTError(Error),
 }
@@ -41,22 +46,126 @@ impl<'r> FromRequest<'r> for Claims {
         let validation = validate_token(&keys.unwrap(), &apikey.unwrap(), &consts.unwrap());
         match validation {
             Err(e) => Outcome::Failure((Status::Unauthorized, AuthError::JWTError(e))),
-            Ok(n) => {
-                if n.claims.get_sub().is_empty() || PathBuf::from(n.claims.get_sub()).is_absolute()
-                {
-                    return Outcome::Failure((Status::Forbidden, AuthError::PathTraversalAttempt));
-                }
-                let mut path = PathBuf::from(consts.unwrap().repo_files_location.clone());
-                path.push(n.claims.get_sub());
-                if !path.exists() {
-                    return Outcome::Failure((Status::Forbidden, AuthError::UsernameInvalidated));
-                }
-                Outcome::Success(n.claims)
-            }
+            Ok(n) => match check_user(request, &n.claims, false).await {
+                Err(e) => Outcome::Failure(e),
+                Ok(_) => Outcome::Success(n.claims),
+            },
+        }
+    }
+}
+
+// Tokens of disabled or deleted accounts and of ended sessions are rejected, even if they did not
+// expire yet. Refresh tokens are only accepted to get new tokens.
+async fn check_user(
+    request: &Request<'_>,
+    claims: &Claims,
+    refresh: bool,
+) -> Result<(), (Status, AuthError)> {
+    if claims.get_sub().is_empty() || PathBuf::from(claims.get_sub()).is_absolute() {
+        return Err((Status::Forbidden, AuthError::PathTraversalAttempt));
+    }
+    let revocations = request.guard::<&State<Revocations>>().await.unwrap();
+    if claims.is_refresh() != refresh || revocations.is_revoked(claims) {
+        return Err((Status::Unauthorized, AuthError::Revoked));
+    }
+    let consts = request.guard::<&State<ZKConfig>>().await.unwrap();
+    let users = request.guard::<&State<UserStore>>().await.unwrap();
+    let mut path = PathBuf::from(consts.repo_files_location.clone());
+    path.push(claims.get_sub());
+    if !path.exists() || users.get(&claims.get_sub()).is_none_or(|u| u.disabled) {
+        return Err((Status::Forbidden, AuthError::UsernameInvalidated));
+    }
+    Ok(())
+}
+
+// Git clients can't keep the private cookie. They authenticate with the username and password of
+// the account, using basic auth. Neither the cookie nor tokens are accepted: the cookie is sent by
+// browsers along with requests of other sites, and CSRF tokens are readable by scripts.
+pub(crate) struct GitClaims(pub(crate) Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GitClaims {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (username, password) = match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(basic_credentials)
+        {
+            Some(c) => c,
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+        };
+        let users = request.guard::<&State<UserStore>>().await.unwrap();
+        if !users.verify(&username, &password) {
+            return Outcome::Failure((Status::Unauthorized, AuthError::WrongUsernamePassword));
+        }
+        let consts = request.guard::<&State<ZKConfig>>().await.unwrap();
+        let claims = Claims::default()
+            .set_iss(consts.hostname.as_str())
+            .set_sub(&username);
+        match check_user(request, &claims, false).await {
+            Err(e) => Outcome::Failure(e),
+            Ok(_) => Outcome::Success(GitClaims(claims)),
+        }
+    }
+}
+
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, value) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(base64::decode(value.trim()).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// Claims of the refresh token, which is kept in its own private cookie
+pub(crate) struct RefreshClaims(pub(crate) Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RefreshClaims {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.cookies().get_private("refresh") {
+            Some(c) => c.value().to_string(),
+            None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+        };
+        let apikey = request.guard::<&State<ApiKey>>().await;
+        let consts = request.guard::<&State<ZKConfig>>().await;
+        match validate_token(&token, apikey.unwrap(), consts.unwrap()) {
+            Err(e) => Outcome::Failure((Status::Unauthorized, AuthError::JWTError(e))),
+            Ok(n) => match check_user(request, &n.claims, true).await {
+                Err(e) => Outcome::Failure(e),
+                Ok(_) => Outcome::Success(RefreshClaims(n.claims)),
+            },
         }
     }
 }
 
+// Claims of an admin. The role in the token is only trusted while the account still has it.
+pub(crate) struct AdminClaims(pub(crate) Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminClaims {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let claims = match request.guard::<Claims>().await {
+            Outcome::Success(c) => c,
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        let users = request.guard::<&State<UserStore>>().await.unwrap();
+        if !claims.is_admin() || !users.get(&claims.get_sub()).is_some_and(|u| u.admin) {
+            return Outcome::Failure((Status::Forbidden, AuthError::NotAdmin));
+        }
+        Outcome::Success(AdminClaims(claims))
+    }
+}
+
 pub(crate) struct CSRFClaims(Claims);
 
 #[rocket::async_trait]
@@ -94,3 +203,55 @@ impl<'r> FromSegments<'r> for APIPath {
         Ok(APIPath(ret))
     }
 }
+
+// Strong entity tags of the If-Match header, without quotes. Weak ones never match (RFC 7232,
+// section 3.1), but still make the header present.
+pub(crate) struct IfMatch(pub(crate) Option<Vec<String>>);
+
+impl IfMatch {
+    pub(crate) fn matches(&self, entry: &Entry) -> bool {
+        match &self.0 {
+            None => true,
+            Some(tags) if tags.iter().any(|t| t == "*") => true,
+            Some(tags) => entry.etag().is_some_and(|e| tags.contains(&e)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tags: Vec<&str> = request
+            .headers()
+            .get("If-Match")
+            .flat_map(|h| h.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect();
+        if tags.is_empty() {
+            return Outcome::Success(IfMatch(None));
+        }
+        let strong = tags
+            .iter()
+            .filter(|t| !t.starts_with("W/"))
+            .map(|t| t.trim_matches('"').to_string())
+            .collect();
+        Outcome::Success(IfMatch(Some(strong)))
+    }
+}
+
+// Value of the Content-Encoding header
+pub(crate) struct ContentEncoding<'r>(pub(crate) Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentEncoding<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ContentEncoding(
+            request.headers().get_one("Content-Encoding"),
+        ))
+    }
+}
@@ -5,6 +5,7 @@ use rocket::request::Request;
 use rocket::response::Responder;
 use rocket::response::Response;
 use rocket::response::Result;
+use std::io::Cursor;
 
 #[derive(Debug)]
 pub(crate) struct ApiResponse {
@@ -27,6 +28,16 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for ApiResponse {
 }
 
 impl ApiResponse {
+    pub(crate) fn set_header(mut self, header: &str, value: &str) -> Self {
+        self.headers.push((header.to_string(), value.to_string()));
+        self
+    }
+
+    pub(crate) fn set_status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+
     pub(crate) fn ok(response: ResponseBodyGeneric) -> ApiResponse {
         ApiResponse {
             headers: Vec::default(),
@@ -59,4 +70,55 @@ impl ApiResponse {
             response,
         }
     }
+
+    pub(crate) fn bad_request(response: ResponseBodyGeneric) -> ApiResponse {
+        ApiResponse {
+            headers: Vec::default(),
+            status: Status::BadRequest,
+            response,
+        }
+    }
+
+    pub(crate) fn conflict(response: ResponseBodyGeneric) -> ApiResponse {
+        ApiResponse {
+            headers: Vec::default(),
+            status: Status::Conflict,
+            response,
+        }
+    }
+
+    pub(crate) fn internal_server_error(response: ResponseBodyGeneric) -> ApiResponse {
+        ApiResponse {
+            headers: Vec::default(),
+            status: Status::InternalServerError,
+            response,
+        }
+    }
+}
+
+// Output of git's smart HTTP services, passed on to the client as is
+#[derive(Debug)]
+pub(crate) struct GitResponse {
+    content_type: ContentType,
+    body: Vec<u8>,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for GitResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> Result<'o> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header("Cache-Control", "no-cache")
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+impl GitResponse {
+    // `kind` is "advertisement" or "result"
+    pub(crate) fn new(service: &str, kind: &str, body: Vec<u8>) -> GitResponse {
+        GitResponse {
+            content_type: ContentType::new("application", format!("x-{}-{}", service, kind)),
+            body,
+        }
+    }
 }
@@ -0,0 +1,249 @@
+use crate::filesystem_interact::check_removable;
+use crate::filesystem_interact::is_hidden_path;
+use crate::filesystem_interact::list_files;
+use crate::filesystem_interact::open;
+use crate::filesystem_interact::Entry;
+use crate::functions::check_claims_csrf;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
+use crate::functions::handle_jwt_error;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::lock_index;
+use crate::git_interact::precondition_lock;
+use crate::requestguards::APIPath;
+use crate::requestguards::AdminClaims;
+use crate::requestguards::AuthError;
+use crate::requestguards::CSRFClaims;
+use crate::requestguards::IfMatch;
+use crate::responders::ApiResponse;
+use crate::routes_get::handle_directory;
+use crate::routes_get::handle_invalid_path;
+use crate::routes_get::handle_keys;
+use crate::routes_get::handle_precondition_failed;
+use crate::routes_get::handle_snapshots;
+use crate::routes_get::handle_trash;
+use crate::routes_get::handle_users;
+use crate::serializables::Claims;
+use crate::state::ApiKey;
+use crate::state::Revocations;
+use crate::state::ZKConfig;
+use crate::trash;
+use crate::users::UserStore;
+use git2::ErrorCode;
+use rocket::State;
+use std::io;
+use std::path::Component;
+use std::path::PathBuf;
+use std::slice;
+
+// All routes mounted at API base path
+// Permanently deletes one (or all, if no id is given) item(s) from the trash
+#[delete("/?trash&<id>", rank = 1)]
+pub(crate) fn purge_trash(
+    id: Option<String>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let purged = match trash::purge(id.as_deref(), &basepath) {
+        Ok(p) => p,
+        Err(e) => return handle_io_error(path, claims, apikey, e),
+    };
+    if purged.is_empty() {
+        return handle_trash(path, claims, apikey, basepath, None);
+    }
+    let message = format!(
+        "Empty trash\n\nPermanently deleted:\n{}",
+        purged
+            .iter()
+            .map(|i| i.path.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    let removed: Vec<PathBuf> = purged.iter().map(|i| i.trash_dir()).collect();
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.commit_paths(&[trash::index_path()], &removed, &message)?;
+        r.find_commit(oid)
+    });
+    match commit {
+        Ok(c) => handle_trash(path, claims, apikey, basepath, Some(c)),
+        Err(e) => handle_git_error(path, claims, apikey, e),
+    }
+}
+
+// Deletes a snapshot. The commits it pointed to stay in the history.
+#[delete("/?<snapshot>", rank = 2)]
+pub(crate) fn snapshot(
+    snapshot: String,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match user_repository(consts, &claims).and_then(|r| r.delete_snapshot(&snapshot)) {
+        Ok(_) => handle_snapshots(path, claims, apikey, basepath, None),
+        Err(e) if e.code() == ErrorCode::NotFound => handle_io_error(
+            path,
+            claims,
+            apikey,
+            io::Error::new(io::ErrorKind::NotFound, "No such snapshot."),
+        ),
+        Err(e) => handle_git_error(path, claims, apikey, e),
+    }
+}
+
+// Deletes an account, but keeps its directory. Admins only, and not their own account.
+#[delete("/?<user>", rank = 3)]
+pub(crate) fn user(
+    user: String,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    users: &State<UserStore>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    if user == claims.get_sub() {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "You can't lock yourself out.");
+        return handle_io_error(path, claims, apikey, e);
+    }
+    match users.delete(&user) {
+        Ok(_) => handle_users(path, claims, apikey, users),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Retires a key, so tokens signed with it are no longer accepted. Admins only.
+#[delete("/?<kid>", rank = 4)]
+pub(crate) fn retire_key(
+    kid: String,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    match apikey.retire(&kid) {
+        Ok(_) => handle_keys(path, claims, apikey),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Ends all sessions of a user, on every device. Admins only.
+#[delete("/?<sessions>", rank = 5)]
+pub(crate) fn sessions(
+    sessions: String,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    users: &State<UserStore>,
+    revocations: &State<Revocations>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    if users.get(&sessions).is_none() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "No such user.");
+        return handle_io_error(path, claims, apikey, e);
+    }
+    match revocations.end_sessions(&sessions) {
+        Ok(_) => handle_users(path, claims, apikey, users),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Moves a file or directory into the trash
+#[delete("/<path..>?<recursive>", rank = 6)]
+pub(crate) fn delete(
+    path: APIPath,
+    recursive: bool,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    if_match: IfMatch,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path.0, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    // The users root directory can't be deleted
+    if is_hidden_path(&path.0)
+        || !path
+            .0
+            .components()
+            .any(|c| matches!(c, Component::Normal(_)))
+    {
+        return handle_invalid_path(path.0, claims, apikey);
+    }
+    let lock = precondition_lock(&basepath);
+    let _guard = lock_index(&lock);
+    match open(&path.0, &basepath) {
+        Some(e) if !if_match.matches(&e) => {
+            handle_precondition_failed(path.0, e, claims, apikey, basepath)
+        }
+        Some(e) => handle_delete(path.0, e, recursive, claims, apikey, consts, basepath),
+        None => handle_invalid_path(path.0, claims, apikey),
+    }
+}
+
+fn handle_delete(
+    path: PathBuf,
+    entry: Entry,
+    recursive: bool,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let item =
+        match check_removable(&entry, recursive).and_then(|_| trash::trash(&entry, &basepath)) {
+            Ok(i) => i,
+            Err(e) => return handle_io_error(path, claims, key, e),
+        };
+    let mut added = list_files(&item.trash_dir(), &basepath, true);
+    added.push(trash::index_path());
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.commit_paths(
+            &added,
+            slice::from_ref(&path),
+            &format!("Delete {}", path.display()),
+        )?;
+        r.find_commit(oid)
+    });
+    let parent = path.parent().unwrap_or(&path).to_path_buf();
+    match (commit, open(&parent, &basepath)) {
+        (Err(e), _) => handle_git_error(path, claims, key, e),
+        (Ok(c), Some(e)) => handle_directory(parent, e, claims, key, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(parent, claims, key),
+    }
+}
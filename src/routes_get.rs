@@ -2,9 +2,18 @@ use crate::filesystem_interact::ls;
 use crate::filesystem_interact::open;
 use crate::filesystem_interact::Entry;
 use crate::filesystem_interact::FType;
+use crate::filesystem_interact::ATTACHMENT_EXTENSIONS;
 use crate::functions::check_claims_csrf;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
 use crate::functions::handle_jwt_error;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::diff_hunks;
+use crate::git_interact::CommitData;
+use crate::git_interact::RepositoryTransaction;
 use crate::requestguards::APIPath;
+use crate::requestguards::AdminClaims;
 use crate::requestguards::AuthError;
 use crate::responders::ApiResponse;
 use crate::serializables::AppState;
@@ -13,12 +22,23 @@ use crate::serializables::DataType;
 use crate::serializables::ResponseBodyGeneric;
 use crate::state::ApiKey;
 use crate::state::ZKConfig;
+use crate::trash;
+use crate::users::UserStore;
+use git2::ErrorCode;
+use rocket::fs::NamedFile;
+use rocket::http::Status;
 use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Value;
 use rocket::State;
+use std::io;
+use std::path::Path;
 use std::path::PathBuf;
 
+const HISTORY_PAGE_SIZE: usize = 20;
+const HISTORY_PAGE_SIZE_MAX: usize = 100;
+
 // All Routes mounted at API base path
-#[get("/", format = "json", rank = 2)]
+#[get("/", format = "json", rank = 21)]
 pub(crate) fn api_index(
     claims: Result<Claims, AuthError>,
     consts: &State<ZKConfig>,
@@ -27,7 +47,7 @@ pub(crate) fn api_index(
     api(APIPath("./".into()), claims, consts, key)
 }
 
-#[get("/<path..>", format = "json", rank = 1)]
+#[get("/<path..>", format = "json", rank = 20)]
 pub(crate) fn api(
     path: APIPath,
     claims: Result<Claims, AuthError>,
@@ -41,6 +61,397 @@ pub(crate) fn api(
     }
 }
 
+// Serves an attachment as is, with the content type derived from its extension
+#[get("/<path..>?raw", rank = 1)]
+pub(crate) async fn raw(
+    path: APIPath,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> Result<NamedFile, ApiResponse> {
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return Err(handle_jwt_error(path.0, consts, key, e));
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path.0, &basepath) {
+        Some(e) if matches!(e.ftype, FType::Attachment) => NamedFile::open(&e.data)
+            .await
+            .map_err(|_| handle_invalid_path(path.0, claims, key)),
+        _ => Err(handle_invalid_path(path.0, claims, key)),
+    }
+}
+
+#[get("/?history&<page>&<per_page>", rank = 4)]
+pub(crate) fn history_index(
+    page: Option<usize>,
+    per_page: Option<usize>,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    history(APIPath("./".into()), page, per_page, claims, consts, key)
+}
+
+// Commits that changed a file or directory, newest first. Pages start at 1.
+#[get("/<path..>?history&<page>&<per_page>", rank = 3)]
+pub(crate) fn history(
+    path: APIPath,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path.0, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page
+        .unwrap_or(HISTORY_PAGE_SIZE)
+        .clamp(1, HISTORY_PAGE_SIZE_MAX);
+    // One more than requested, to tell whether there is a next page
+    let commits = RepositoryTransaction::from(basepath.to_str().unwrap_or_default())
+        .and_then(|r| r.history(&path.0, (page - 1) * per_page, per_page + 1));
+    match commits {
+        Ok(c) if c.is_empty() && open(&path.0, &basepath).is_none() => {
+            handle_invalid_path(path.0, claims, key)
+        }
+        Ok(mut c) => {
+            let more = c.len() > per_page;
+            c.truncate(per_page);
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.0.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(
+                    json!({"page": page, "per_page": per_page, "more": more, "commits": c}),
+                    DataType::History,
+                )
+                .set_appstate(AppState::default().set_authorized(true));
+            ApiResponse::ok(res)
+        }
+        Err(e) => handle_git_error(path.0, claims, key, e),
+    }
+}
+
+#[get("/?<rev>", rank = 11)]
+pub(crate) fn revision_index(
+    rev: String,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    revision(APIPath("./".into()), rev, claims, consts, key)
+}
+
+// A note or directory as of a past commit or snapshot. Read-only, as it is not the current
+// version.
+#[get("/<path..>?<rev>", rank = 5)]
+pub(crate) fn revision(
+    path: APIPath,
+    rev: String,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path.0, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let repo = RepositoryTransaction::from(basepath.to_str().unwrap_or_default());
+    let entry = match path.0.extension().unwrap_or_default() == "md" {
+        true => repo
+            .and_then(|r| r.file_at(&path.0, &rev))
+            .map(|(c, content)| (revision_file(&path.0, &c, &content), DataType::MD, c)),
+        false => repo
+            .and_then(|r| r.dir_at(&path.0, &rev))
+            .map(|(c, entries)| (revision_directory(&path.0, entries), DataType::Directory, c)),
+    };
+    match entry {
+        Ok((inner, dtype, commit)) => {
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.0.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(inner, dtype)
+                .set_appstate(
+                    AppState::default()
+                        .set_authorized(true)
+                        .set_commit(Some(commit)),
+                );
+            ApiResponse::ok(res)
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => handle_invalid_path(path.0, claims, key),
+        Err(e) => handle_git_error(path.0, claims, key, e),
+    }
+}
+
+fn revision_file(path: &Path, commit: &CommitData, content: &[u8]) -> Value {
+    json!({
+        "name": path.file_name().unwrap_or_default().to_string_lossy(),
+        "data": {
+            "size": content.len(),
+            "modified": commit.time,
+            "content": String::from_utf8_lossy(content),
+            "readonly": true,
+        },
+        "ftype": FType::MDFile,
+        "url": path,
+    })
+}
+
+// Shaped like a directory listing, but without metadata of the entries. Hidden files and files of
+// unknown types are left out, as in the working directory.
+fn revision_directory(path: &Path, entries: Vec<(String, bool)>) -> Value {
+    let entry = |name: &str, url: &Path, ftype: FType| json!({"name": name, "data": {"readonly": true}, "ftype": ftype, "url": url});
+    let (mut mds, mut dirs, mut attachments) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, is_dir) in entries.iter().filter(|(n, _)| !n.starts_with('.')) {
+        let url = path.join(name);
+        let extension = url
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        match (is_dir, extension.as_str()) {
+            (true, _) => dirs.push(entry(name, &url, FType::Directory)),
+            (false, "md") => mds.push(entry(name, &url, FType::MDFile)),
+            (false, e) if ATTACHMENT_EXTENSIONS.contains(&e) => {
+                attachments.push(entry(name, &url, FType::Attachment))
+            }
+            _ => (),
+        }
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    json!({
+        "head": entry(&name, path, FType::Directory),
+        "mds": mds,
+        "dirs": dirs,
+        "attachments": attachments,
+    })
+}
+
+// Changes of a note between two commits (`<from>..<to>`), or between a commit and the working
+// directory (`<from>..` or just `<from>`). With `unified`, a unified diff is returned instead of
+// the hunks.
+#[get("/<path..>?<diff>&<unified>", rank = 6)]
+pub(crate) fn diff(
+    path: APIPath,
+    diff: String,
+    unified: bool,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path.0, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let (from, to) = match diff.split_once("..") {
+        Some((from, "")) => (from, None),
+        Some((from, to)) => (from, Some(to)),
+        None => (diff.as_str(), None),
+    };
+    if from.is_empty() || path.0.extension().unwrap_or_default() != "md" {
+        return handle_io_error(
+            path.0,
+            claims,
+            key,
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid diff request."),
+        );
+    }
+    let diff = RepositoryTransaction::from(basepath.to_str().unwrap_or_default()).and_then(|r| {
+        let (from_commit, old) = r.file_content(&path.0, Some(from))?;
+        let (to_commit, new) = r.file_content(&path.0, to)?;
+        let (hunks, patch) = diff_hunks(&path.0, &old, &new)?;
+        Ok(match unified {
+            true => json!({"from": from_commit, "to": to_commit, "unified": patch}),
+            false => json!({"from": from_commit, "to": to_commit, "hunks": hunks}),
+        })
+    });
+    match diff {
+        Ok(d) => {
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.0.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(d, DataType::Diff)
+                .set_appstate(AppState::default().set_authorized(true));
+            ApiResponse::ok(res)
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => handle_invalid_path(path.0, claims, key),
+        Err(e) => handle_git_error(path.0, claims, key, e),
+    }
+}
+
+// Which commit last changed each line of a note
+#[get("/<path..>?blame", rank = 7)]
+pub(crate) fn blame(
+    path: APIPath,
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path.0, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path.0, &basepath) {
+        Some(e) if matches!(e.ftype, FType::MDFile) => (),
+        _ => return handle_invalid_path(path.0, claims, key),
+    }
+    let blame = RepositoryTransaction::from(basepath.to_str().unwrap_or_default())
+        .and_then(|r| r.blame(&path.0));
+    match blame {
+        Ok(b) => {
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.0.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(json!(b), DataType::Blame)
+                .set_appstate(AppState::default().set_authorized(true));
+            ApiResponse::ok(res)
+        }
+        // Notes that were never committed
+        Err(e) if e.code() == ErrorCode::NotFound => handle_invalid_path(path.0, claims, key),
+        Err(e) => handle_git_error(path.0, claims, key, e),
+    }
+}
+
+#[get("/?profile", rank = 8)]
+pub(crate) fn profile(
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    handle_profile(path, claims.unwrap(), key, consts)
+}
+
+pub(crate) fn handle_profile(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    match user_repository(consts, &claims).and_then(|r| r.profile()) {
+        Ok(p) => {
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(json!(p), DataType::Profile)
+                .set_appstate(AppState::default().set_authorized(true));
+            ApiResponse::ok(res)
+        }
+        Err(e) => handle_git_error(path, claims, key, e),
+    }
+}
+
+// The remote the users repository is synced with
+#[get("/?remote", rank = 9)]
+pub(crate) fn remote(
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    handle_remote(path, claims.unwrap(), key, consts)
+}
+
+pub(crate) fn handle_remote(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let remote = user_repository(consts, &claims)
+        .and_then(|r| Ok((r.remote_url()?, r.remote_credentials()?)));
+    // Passwords and keys are never sent back
+    match remote {
+        Ok((u, c)) => {
+            let res = ResponseBodyGeneric::default()
+                .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+                .set_inner(
+                    json!({
+                        "url": u,
+                        "username": c.username,
+                        "password": c.password.is_some(),
+                        "ssh_key": c.ssh_key.is_some(),
+                    }),
+                    DataType::Remote,
+                )
+                .set_appstate(AppState::default().set_authorized(true));
+            ApiResponse::ok(res)
+        }
+        Err(e) => handle_git_error(path, claims, key, e),
+    }
+}
+
+// Lists the items in the trash
+#[get("/?trash", rank = 2)]
+pub(crate) fn list_trash(
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    handle_trash(path, claims, key, basepath, None)
+}
+
+// Lists the snapshots of the repository, newest first
+#[get("/?snapshots", rank = 10)]
+pub(crate) fn snapshots(
+    claims: Result<Claims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    handle_snapshots(path, claims, key, basepath, None)
+}
+
+// Lists all accounts. Admins only.
+#[get("/?users", rank = 13)]
+pub(crate) fn users(
+    claims: Result<AdminClaims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+    users: &State<UserStore>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    handle_users(path, claims.unwrap(), key, users)
+}
+
+// Lists the keys tokens are signed with, without their secrets. Admins only.
+#[get("/?keys", rank = 14)]
+pub(crate) fn keys(
+    claims: Result<AdminClaims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, None) {
+        return handle_jwt_error(path, consts, key, e);
+    }
+    handle_keys(path, claims.unwrap(), key)
+}
+
 fn handle_dir_file(
     path: PathBuf,
     claims: Claims,
@@ -50,22 +461,37 @@ fn handle_dir_file(
     let mut basepath = PathBuf::from(&consts.repo_files_location);
     basepath.push(claims.get_sub());
     if let Some(e) = open(&path, &basepath) {
-        match e.ftype {
-            FType::MDFile => handle_markdown_file(path, e, claims, key),
-            FType::Directory => handle_directory(path, e, claims, key, basepath),
-        }
+        handle_entry(path, e, claims, key, basepath, None)
     } else {
         handle_invalid_path(path, claims, key)
     }
 }
 
-fn handle_directory(
+pub(crate) fn handle_entry(
+    path: PathBuf,
+    entry: Entry,
+    claims: Claims,
+    key: &State<ApiKey>,
+    basepath: PathBuf,
+    commit: Option<CommitData>,
+) -> ApiResponse {
+    match entry.ftype {
+        FType::MDFile => handle_markdown_file(path, entry, claims, key, basepath, commit),
+        FType::Directory => handle_directory(path, entry, claims, key, basepath, commit),
+        FType::Attachment => handle_attachment(path, entry, claims, key, basepath, commit),
+    }
+}
+
+pub(crate) fn handle_directory(
     path: PathBuf,
     dir: Entry,
     claims: Claims,
     key: &State<ApiKey>,
     basepath: PathBuf,
+    commit: Option<CommitData>,
 ) -> ApiResponse {
+    let etag = dir.etag();
+    let appstate = repository_appstate(&basepath, &path, commit);
     let res = ResponseBodyGeneric::default()
         .set_apiurl(path.to_str().unwrap_or_default(), &key, &claims)
         .set_inner(
@@ -73,25 +499,158 @@ fn handle_directory(
             DataType::Directory,
         )
         .set_history(true, path.to_str().unwrap_or_default())
-        .set_appstate(AppState::default().set_authorized(true));
-    ApiResponse::ok(res)
+        .set_appstate(appstate);
+    set_etag(ApiResponse::ok(res), etag)
 }
 
-fn handle_markdown_file(
+pub(crate) fn handle_markdown_file(
     path: PathBuf,
     mdfile: Entry,
     claims: Claims,
     key: &State<ApiKey>,
+    basepath: PathBuf,
+    commit: Option<CommitData>,
 ) -> ApiResponse {
+    let etag = mdfile.etag();
+    let appstate = repository_appstate(&basepath, &path, commit);
     let res = ResponseBodyGeneric::default()
         .set_apiurl(path.to_str().unwrap_or_default(), &key, &claims)
         .set_inner(mdfile.json(), DataType::MD)
         .set_history(true, path.to_str().unwrap_or_default())
+        .set_appstate(appstate);
+    set_etag(ApiResponse::ok(res), etag)
+}
+
+fn handle_attachment(
+    path: PathBuf,
+    attachment: Entry,
+    claims: Claims,
+    key: &State<ApiKey>,
+    basepath: PathBuf,
+    commit: Option<CommitData>,
+) -> ApiResponse {
+    let etag = attachment.etag();
+    let appstate = repository_appstate(&basepath, &path, commit);
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(attachment.json(), DataType::Attachment)
+        .set_appstate(appstate);
+    set_etag(ApiResponse::ok(res), etag)
+}
+
+pub(crate) fn handle_trash(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    basepath: PathBuf,
+    commit: Option<CommitData>,
+) -> ApiResponse {
+    let items = match trash::list(&basepath) {
+        Ok(i) => i,
+        Err(e) => return handle_io_error(path, claims, key, e),
+    };
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(json!(items), DataType::Trash)
+        .set_appstate(AppState::default().set_authorized(true).set_commit(commit));
+    ApiResponse::ok(res)
+}
+
+pub(crate) fn handle_snapshots(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    basepath: PathBuf,
+    commit: Option<CommitData>,
+) -> ApiResponse {
+    let snapshots = match RepositoryTransaction::from(basepath.to_str().unwrap_or_default())
+        .and_then(|r| r.snapshots())
+    {
+        Ok(s) => s,
+        Err(e) => return handle_git_error(path, claims, key, e),
+    };
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(json!(snapshots), DataType::Snapshots)
+        .set_appstate(AppState::default().set_authorized(true).set_commit(commit));
+    ApiResponse::ok(res)
+}
+
+pub(crate) fn handle_users(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+    users: &UserStore,
+) -> ApiResponse {
+    let users: Vec<Value> = users.list().iter().map(|u| u.json()).collect();
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(json!(users), DataType::Users)
         .set_appstate(AppState::default().set_authorized(true));
     ApiResponse::ok(res)
 }
 
-fn handle_invalid_path(path: PathBuf, claims: Claims, key: &State<ApiKey>) -> ApiResponse {
+pub(crate) fn handle_keys(path: PathBuf, claims: Claims, key: &State<ApiKey>) -> ApiResponse {
+    let current = key.current().kid;
+    let keys: Vec<Value> = key
+        .list()
+        .iter()
+        .map(|k| {
+            let mut json = k.json();
+            json["current"] = json!(k.kid == current);
+            json
+        })
+        .collect();
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(json!(keys), DataType::Keys)
+        .set_appstate(AppState::default().set_authorized(true));
+    ApiResponse::ok(res)
+}
+
+// The last commit of the repository and the last one that changed `path`, if that was recent. A
+// commit that was just made for the request is both.
+fn repository_appstate(basepath: &Path, path: &Path, commit: Option<CommitData>) -> AppState {
+    let (commit, path_commit) = match commit {
+        Some(c) => (Some(c.clone()), Some(c)),
+        None => match RepositoryTransaction::from(basepath.to_str().unwrap_or_default()) {
+            Ok(r) => (
+                r.find_last_commit().ok(),
+                r.last_change(path).ok().flatten(),
+            ),
+            Err(_) => (None, None),
+        },
+    };
+    AppState::default()
+        .set_authorized(true)
+        .set_commit(commit)
+        .set_path_commit(path_commit)
+}
+
+// Responds with the current version of a file or directory, if it changed since the client last
+// saw it
+pub(crate) fn handle_precondition_failed(
+    path: PathBuf,
+    entry: Entry,
+    claims: Claims,
+    key: &State<ApiKey>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    handle_entry(path, entry, claims, key, basepath, None).set_status(Status::PreconditionFailed)
+}
+
+fn set_etag(res: ApiResponse, etag: Option<String>) -> ApiResponse {
+    match etag {
+        Some(e) => res.set_header("ETag", &format!("\"{}\"", e)),
+        None => res,
+    }
+}
+
+pub(crate) fn handle_invalid_path(
+    path: PathBuf,
+    claims: Claims,
+    key: &State<ApiKey>,
+) -> ApiResponse {
     let res = ResponseBodyGeneric::default()
         .set_apiurl(path.to_str().unwrap_or_default(), &key, &claims)
         .set_inner(
@@ -0,0 +1,481 @@
+use crate::functions::commit_pending;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
+use crate::functions::handle_jwt_error;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::lock_index;
+use crate::git_interact::repository_lock;
+use crate::requestguards::AuthError;
+use crate::requestguards::ContentEncoding;
+use crate::requestguards::GitClaims;
+use crate::responders::ApiResponse;
+use crate::responders::GitResponse;
+use crate::serializables::AppState;
+use crate::serializables::Claims;
+use crate::serializables::DataType;
+use crate::serializables::ResponseBodyGeneric;
+use crate::state::ApiKey;
+use crate::state::PendingCommits;
+use crate::state::ZKConfig;
+use flate2::FlateReadExt;
+use rocket::data::ByteUnit;
+use rocket::data::Data;
+use rocket::data::Limits;
+use rocket::data::ToByteUnit;
+use rocket::serde::json::serde_json::json;
+use rocket::tokio::task;
+use rocket::State;
+use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::fs::read_to_string;
+use std::fs::set_permissions;
+use std::fs::write;
+use std::fs::Permissions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+
+// Git's smart HTTP protocol, so users can clone, pull and push their repository with any git client:
+// `git clone https://<host>/api/git/<user>`. The actual work is done by git-upload-pack and
+// git-receive-pack.
+
+const UPLOAD_PACK: &str = "git-upload-pack";
+const RECEIVE_PACK: &str = "git-receive-pack";
+
+// Pushes are checked before any ref is updated. fsck rejects trees with entries like `.git` or `..`,
+// and the pre-receive hook rejects symbolic links, so nothing can be checked out outside the
+// working directory. The working directory is updated with the pushed branch, unless it has
+// uncommitted changes.
+const RECEIVE_PACK_CONFIG: [&str; 5] = [
+    "receive.fsckObjects=true",
+    "receive.denyCurrentBranch=updateInstead",
+    "receive.denyDeletes=true",
+    "receive.denyNonFastForwards=true",
+    "core.symlinks=false",
+];
+
+// Symbolic links could point anywhere on the server, and the API would follow them. The trash is
+// only changed by the server itself. Objects of the push are already readable by the hook, but no
+// ref was updated yet.
+const PRE_RECEIVE_HOOK: &str = r#"#!/bin/sh
+# Installed by the server, changes are overwritten
+while read old new ref; do
+    case "$new" in *[!0]*) ;; *) continue ;; esac
+    for commit in $(git rev-list "$new" --not --all); do
+        if git ls-tree -r "$commit" | grep -q '^120000 '; then
+            echo "Symbolic links are not allowed ($commit)." >&2
+            exit 1
+        fi
+    done
+    if [ "$(git rev-parse -q --verify "$new:.trash")" != "$(git rev-parse -q --verify HEAD:.trash)" ]; then
+        echo "Changes to the trash are not allowed." >&2
+        exit 1
+    fi
+done
+"#;
+
+// Size of pushed packs, unless set with `limits.git`
+fn pack_limit(limits: &Limits) -> ByteUnit {
+    limits.get("git").unwrap_or_else(|| 256.mebibytes())
+}
+
+#[get("/git/<user>/info/refs?<service>", rank = 12)]
+pub(crate) async fn info_refs(
+    user: &str,
+    service: &str,
+    claims: Result<GitClaims, AuthError>,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> Result<GitResponse, ApiResponse> {
+    let path = PathBuf::from("./");
+    let claims = check_git_claims(claims, user, consts, key)?;
+    let basepath = prepare_repository(service, &claims, consts, key)?;
+    let name = service.to_string();
+    let advertisement = task::spawn_blocking(move || run_service(&name, &basepath, None))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|r| r);
+    match advertisement {
+        Ok(refs) => {
+            let header = format!("# service={}\n", service);
+            let mut body = format!("{:04x}{}0000", header.len() + 4, header).into_bytes();
+            body.extend(refs);
+            Ok(GitResponse::new(service, "advertisement", body))
+        }
+        Err(e) => Err(handle_io_error(path, claims, key, e)),
+    }
+}
+
+#[post("/git/<user>/<service>", data = "<data>", rank = 11)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn service(
+    user: &str,
+    service: &str,
+    data: Data<'_>,
+    claims: Result<GitClaims, AuthError>,
+    limits: &Limits,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+    pending: &State<PendingCommits>,
+    encoding: ContentEncoding<'_>,
+) -> Result<GitResponse, ApiResponse> {
+    let path = PathBuf::from("./");
+    let claims = check_git_claims(claims, user, consts, key)?;
+    let basepath = prepare_repository(service, &claims, consts, key)?;
+    let input = match data.open(pack_limit(limits)).into_bytes().await {
+        Ok(i) if i.is_complete() => i.into_inner(),
+        Ok(_) => {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "Pack is too large.");
+            return Err(handle_io_error(path, claims, key, e));
+        }
+        Err(e) => return Err(handle_io_error(path, claims, key, e)),
+    };
+    // Changes that are not committed yet would make the push fail
+    if service == RECEIVE_PACK {
+        if let Err(e) = commit_pending(consts, pending, &claims.get_sub(), None) {
+            return Err(handle_git_error(path, claims, key, e));
+        }
+    }
+    let gzip = encoding
+        .0
+        .is_some_and(|e| e.trim().eq_ignore_ascii_case("gzip"));
+    let name = service.to_string();
+    let result = task::spawn_blocking(move || {
+        // Pushes update the index of the working directory
+        let lock = repository_lock(&basepath);
+        let _guard = lock_index(&lock);
+        let input = match gzip {
+            true => {
+                let mut decoded = Vec::new();
+                input.as_slice().gz_decode()?.read_to_end(&mut decoded)?;
+                decoded
+            }
+            false => input,
+        };
+        run_service(&name, &basepath, Some(input))
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|r| r);
+    match result {
+        Ok(body) => Ok(GitResponse::new(service, "result", body)),
+        Err(e) => Err(handle_io_error(path, claims, key, e)),
+    }
+}
+
+// Only the owner of a repository may access it
+#[allow(clippy::result_large_err)]
+fn check_git_claims(
+    claims: Result<GitClaims, AuthError>,
+    user: &str,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> Result<Claims, ApiResponse> {
+    let path = PathBuf::from("./");
+    let claims = match claims {
+        Ok(GitClaims(c)) => c,
+        Err(e) => {
+            return Err(handle_jwt_error(path, consts, key, &e)
+                .set_header("WWW-Authenticate", "Basic realm=\"zk\""))
+        }
+    };
+    if claims.get_sub() != user {
+        let res = ResponseBodyGeneric::default()
+            .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+            .set_inner(
+                json!({"message": "Not your repository."}),
+                DataType::ErrorMessage,
+            )
+            .set_appstate(AppState::default().set_authorized(true));
+        return Err(ApiResponse::forbidden(res));
+    }
+    Ok(claims)
+}
+
+// Makes sure the repository exists before git accesses it
+#[allow(clippy::result_large_err)]
+fn prepare_repository(
+    service: &str,
+    claims: &Claims,
+    consts: &State<ZKConfig>,
+    key: &State<ApiKey>,
+) -> Result<PathBuf, ApiResponse> {
+    let path = PathBuf::from("./");
+    if service != UPLOAD_PACK && service != RECEIVE_PACK {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "Unknown service.");
+        return Err(handle_io_error(path, claims.clone(), key, e));
+    }
+    if let Err(e) = user_repository(consts, claims) {
+        return Err(handle_git_error(path, claims.clone(), key, e));
+    }
+    let basepath = user_basepath(consts, claims);
+    if let Err(e) = install_hook(&hooks_path(&basepath)) {
+        return Err(handle_io_error(path, claims.clone(), key, e));
+    }
+    Ok(basepath)
+}
+
+fn hooks_path(basepath: &Path) -> PathBuf {
+    basepath.join(".git").join("hooks")
+}
+
+fn install_hook(hooks: &Path) -> io::Result<()> {
+    let hook = hooks.join("pre-receive");
+    if read_to_string(&hook).ok().as_deref() == Some(PRE_RECEIVE_HOOK) {
+        return Ok(());
+    }
+    create_dir_all(hooks)?;
+    write(&hook, PRE_RECEIVE_HOOK)?;
+    set_permissions(&hook, Permissions::from_mode(0o755))
+}
+
+// Runs `service` for the repository at `basepath`. Without input, the refs are advertised.
+fn run_service(service: &str, basepath: &Path, input: Option<Vec<u8>>) -> io::Result<Vec<u8>> {
+    let mut command = Command::new("git");
+    if service == RECEIVE_PACK {
+        for config in RECEIVE_PACK_CONFIG.iter() {
+            command.arg("-c").arg(config);
+        }
+        // Hooks configured elsewhere are ignored
+        let mut hooks = OsString::from("core.hooksPath=");
+        hooks.push(hooks_path(basepath));
+        command.arg("-c").arg(hooks);
+    }
+    command
+        .arg(service.trim_start_matches("git-"))
+        .arg("--stateless-rpc");
+    if input.is_none() {
+        command.arg("--advertise-refs");
+    }
+    let mut child = command
+        .arg(basepath)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Written from another thread, as git may start to respond before it read all of the input
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input.unwrap_or_default()));
+    let output = child.wait_with_output()?;
+    let written = writer
+        .join()
+        .map_err(|_| io::Error::other("Writing to git failed."))?;
+    // Git failing explains more than the input it didn't read
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr)));
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serializables::Claims;
+    use crate::state::ApiKey;
+    use crate::state::PendingCommits;
+    use crate::state::Revocations;
+    use crate::state::ZKConfig;
+    use crate::tokens::issue_token;
+    use crate::users::UserStore;
+    use figment::providers::Format;
+    use figment::providers::Toml;
+    use figment::Figment;
+    use git2::Oid;
+    use git2::Repository;
+    use git2::Signature;
+    use rocket::http::Cookie;
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::read_to_string;
+    use std::fs::remove_dir_all;
+    use std::fs::symlink_metadata;
+    use std::io::Write;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    const PASSWORD: &str = "secret";
+
+    fn client(base: &Path) -> Client {
+        let notes = base.join("notes");
+        create_dir_all(notes.join("alice")).unwrap();
+        let config: ZKConfig = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                path = "/api"
+                cors = false
+                repo_files_location = "{}"
+                hostname = "localhost"
+                admin_password = "{}"
+                "#,
+                notes.display(),
+                PASSWORD
+            )))
+            .extract()
+            .unwrap();
+        let rocket = rocket::build()
+            .manage(UserStore::open(&config).unwrap())
+            .manage(ApiKey::open(&config).unwrap())
+            .manage(Revocations::open(&config).unwrap())
+            .manage(PendingCommits::default())
+            .manage(config)
+            .mount("/api", routes![super::info_refs, super::service]);
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn basic_auth(password: &str) -> Header<'static> {
+        let credentials = base64::encode(format!("alice:{}", password));
+        Header::new("Authorization", format!("Basic {}", credentials))
+    }
+
+    fn pkt_line(line: &str) -> Vec<u8> {
+        format!("{:04x}{}", line.len() + 4, line).into_bytes()
+    }
+
+    // The text of all pkt-lines up to the first flush after `skip` flushes, and what follows
+    fn read_pkt_lines(mut data: &[u8], mut skip: usize) -> (Vec<String>, &[u8]) {
+        let mut lines = Vec::new();
+        while data.len() >= 4 {
+            let len = usize::from_str_radix(std::str::from_utf8(&data[..4]).unwrap(), 16).unwrap();
+            if len == 0 {
+                data = &data[4..];
+                if skip == 0 {
+                    break;
+                }
+                skip -= 1;
+                continue;
+            }
+            lines.push(String::from_utf8_lossy(&data[4..len]).to_string());
+            data = &data[len..];
+        }
+        (lines, data)
+    }
+
+    // The branch HEAD points to, and its commit
+    fn advertised_head(client: &Client) -> (String, Oid) {
+        let response = client
+            .get("/api/git/alice/info/refs?service=git-upload-pack")
+            .header(basic_auth(PASSWORD))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let (lines, _) = read_pkt_lines(&response.into_bytes().unwrap(), 1);
+        let line = lines.iter().find(|l| l.contains(" refs/heads/")).unwrap();
+        let (oid, branch) = line.trim_end().split_once(' ').unwrap();
+        (branch.to_string(), Oid::from_str(oid).unwrap())
+    }
+
+    fn fetch(client: &Client, local: &Repository, want: Oid) {
+        let mut body = pkt_line(&format!("want {}\n", want));
+        body.extend(b"0000");
+        body.extend(pkt_line("done\n"));
+        let response = client
+            .post("/api/git/alice/git-upload-pack")
+            .header(basic_auth(PASSWORD))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let data = response.into_bytes().unwrap();
+        let pack = &data[8..]; // After "0008NAK\n"
+        assert!(pack.starts_with(b"PACK"));
+        let odb = local.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        writer.write_all(pack).unwrap();
+        writer.commit().unwrap();
+    }
+
+    // Commits a file on top of `parent`, without updating any ref
+    fn commit_file(local: &Repository, parent: Oid, name: &str, content: &str, mode: i32) -> Oid {
+        let parent = local.find_commit(parent).unwrap();
+        let blob = local.blob(content.as_bytes()).unwrap();
+        let mut builder = local.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        builder.insert(name, blob, mode).unwrap();
+        let tree = local.find_tree(builder.write().unwrap()).unwrap();
+        let signature = Signature::now("alice", "alice@localhost").unwrap();
+        local
+            .commit(None, &signature, &signature, name, &tree, &[&parent])
+            .unwrap()
+    }
+
+    // Returns the report of receive-pack
+    fn push(client: &Client, local: &Repository, branch: &str, old: Oid, new: Oid) -> Vec<String> {
+        let mut builder = local.packbuilder().unwrap();
+        builder.insert_commit(new).unwrap();
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack).unwrap();
+        let mut body = pkt_line(&format!("{} {} {}\0report-status\n", old, new, branch));
+        body.extend(b"0000");
+        body.extend(pack.iter());
+        let response = client
+            .post("/api/git/alice/git-receive-pack")
+            .header(basic_auth(PASSWORD))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        read_pkt_lines(&response.into_bytes().unwrap(), 0).0
+    }
+
+    #[test]
+    fn clone_and_push() {
+        let base: PathBuf = env::temp_dir().join(format!("zk-git-{}", std::process::id()));
+        let client = client(&base);
+        let working_dir = base.join("notes").join("alice");
+
+        let response = client
+            .get("/api/git/alice/info/refs?service=git-upload-pack")
+            .header(basic_auth("wrong"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // The session cookie is not accepted either, browsers send it along with other sites requests
+        let claims = Claims::default().set_iss("localhost").set_sub("alice");
+        let apikey = client.rocket().state::<ApiKey>().unwrap();
+        let cookie = Cookie::new("jwt", issue_token(&claims, apikey).unwrap());
+        let response = client
+            .get("/api/git/alice/info/refs?service=git-upload-pack")
+            .private_cookie(cookie)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Clone
+        let (branch, head) = advertised_head(&client);
+        let local = Repository::init_bare(base.join("clone.git")).unwrap();
+        fetch(&client, &local, head);
+        assert!(local.find_commit(head).is_ok());
+
+        // Push a note, it is checked out in the working directory
+        let pushed = commit_file(&local, head, "pushed.md", "pushed\n", 0o100644);
+        let report = push(&client, &local, &branch, head, pushed);
+        assert!(report.iter().any(|l| l == "unpack ok\n"), "{:?}", report);
+        assert!(report.iter().any(|l| l.starts_with("ok ")), "{:?}", report);
+        assert_eq!(
+            read_to_string(working_dir.join("pushed.md")).unwrap(),
+            "pushed\n"
+        );
+        assert_eq!(advertised_head(&client).1, pushed);
+
+        // Symbolic links are rejected
+        let evil = commit_file(&local, pushed, "evil.md", "/etc/passwd", 0o120000);
+        let report = push(&client, &local, &branch, pushed, evil);
+        assert!(report.iter().any(|l| l.starts_with("ng ")), "{:?}", report);
+        assert!(symlink_metadata(working_dir.join("evil.md")).is_err());
+        assert_eq!(advertised_head(&client).1, pushed);
+
+        // So are changes to the trash
+        let trash = commit_file(&local, pushed, ".trash", "[]", 0o100644);
+        let report = push(&client, &local, &branch, pushed, trash);
+        assert!(report.iter().any(|l| l.starts_with("ng ")), "{:?}", report);
+        assert!(!working_dir.join(".trash").exists());
+        assert_eq!(advertised_head(&client).1, pushed);
+
+        remove_dir_all(&base).ok();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::deserializables::PatchAttempt;
+use crate::filesystem_interact::is_hidden_path;
+use crate::filesystem_interact::open;
+use crate::filesystem_interact::replace_lines;
+use crate::filesystem_interact::Entry;
+use crate::filesystem_interact::FType;
+use crate::functions::check_claims_csrf;
+use crate::functions::commit_or_defer;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
+use crate::functions::handle_jwt_error;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::lock_index;
+use crate::git_interact::precondition_lock;
+use crate::requestguards::APIPath;
+use crate::requestguards::AuthError;
+use crate::requestguards::CSRFClaims;
+use crate::requestguards::IfMatch;
+use crate::responders::ApiResponse;
+use crate::routes_get::handle_invalid_path;
+use crate::routes_get::handle_markdown_file;
+use crate::routes_get::handle_precondition_failed;
+use crate::serializables::AppState;
+use crate::serializables::Claims;
+use crate::serializables::DataType;
+use crate::serializables::ResponseBodyGeneric;
+use crate::state::ApiKey;
+use crate::state::PendingCommits;
+use crate::state::ZKConfig;
+use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::path::PathBuf;
+
+// All routes mounted at API base path
+#[patch("/<path..>", format = "json", data = "<message>", rank = 1)]
+pub(crate) fn patch(
+    path: APIPath,
+    message: Json<PatchAttempt>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    if_match: IfMatch,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path.0, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    if is_hidden_path(&path.0) {
+        return handle_invalid_path(path.0, claims, apikey);
+    }
+    let lock = precondition_lock(&basepath);
+    let _guard = lock_index(&lock);
+    match open(&path.0, &basepath) {
+        Some(e) if matches!(e.ftype, FType::MDFile) && !if_match.matches(&e) => {
+            handle_precondition_failed(path.0, e, claims, apikey, basepath)
+        }
+        Some(e) if matches!(e.ftype, FType::MDFile) => handle_patch(
+            path.0,
+            e,
+            message.into_inner(),
+            claims,
+            apikey,
+            consts,
+            pending,
+            basepath,
+        ),
+        _ => handle_invalid_path(path.0, claims, apikey),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_patch(
+    path: PathBuf,
+    mdfile: Entry,
+    patch: PatchAttempt,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let mut repo = match user_repository(consts, &claims) {
+        Ok(r) => r,
+        Err(e) => return handle_git_error(path, claims, key, e),
+    };
+    let applied = match patch {
+        PatchAttempt::Diff(diff) => repo
+            .apply_patch(&path, &diff)
+            .map_err(|e| e.message().to_string()),
+        PatchAttempt::Lines(mut replacements) => {
+            let content = match read_to_string(&mdfile.data) {
+                Ok(c) => c,
+                Err(e) => return handle_io_error(path, claims, key, e),
+            };
+            match replace_lines(&content, &mut replacements) {
+                Ok(c) => write(&mdfile.data, c).map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if let Err(e) = applied {
+        return handle_patch_conflict(path, mdfile, claims, key, e);
+    }
+    let message = format!("Patch {}", path.display());
+    match commit_or_defer(&mut repo, &path, &message, consts, pending, &claims) {
+        Ok(c) => handle_markdown_file(path, mdfile, claims, key, basepath, c),
+        Err(e) => handle_git_error(path, claims, key, e),
+    }
+}
+
+fn handle_patch_conflict(
+    path: PathBuf,
+    mdfile: Entry,
+    claims: Claims,
+    key: &State<ApiKey>,
+    message: String,
+) -> ApiResponse {
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(
+            json!({"message": "Patch does not apply.", "details": message, "current": mdfile.json()}),
+            DataType::ErrorMessage,
+        )
+        .set_appstate(AppState::default().set_authorized(true));
+    ApiResponse::conflict(res)
+}
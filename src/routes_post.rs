@@ -1,65 +1,215 @@
 use crate::deserializables::AuthAttempt;
+use crate::deserializables::CommitAttempt;
 use crate::deserializables::CreateAttempt;
+use crate::deserializables::MoveAttempt;
+use crate::deserializables::RestoreAttempt;
+use crate::deserializables::SnapshotAttempt;
+use crate::deserializables::UploadAttempt;
+use crate::deserializables::UserAttempt;
+use crate::filesystem_interact;
+use crate::filesystem_interact::attachment_path;
+use crate::filesystem_interact::is_hidden_path;
+use crate::filesystem_interact::list_files;
+use crate::filesystem_interact::open;
+use crate::filesystem_interact::Entry;
+use crate::filesystem_interact::FType;
 use crate::functions::check_claims_csrf;
+use crate::functions::commit_pending;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
 use crate::functions::handle_jwt_error;
+use crate::functions::is_remote_url;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::SyncOutcome;
+use crate::links::normalize;
+use crate::links::rewrite_links;
 use crate::requestguards::APIPath;
+use crate::requestguards::AdminClaims;
 use crate::requestguards::AuthError;
 use crate::requestguards::CSRFClaims;
+use crate::requestguards::IfMatch;
+use crate::requestguards::RefreshClaims;
 use crate::responders::ApiResponse;
 use crate::routes_get::api;
+use crate::routes_get::handle_entry;
+use crate::routes_get::handle_invalid_path;
+use crate::routes_get::handle_keys;
+use crate::routes_get::handle_precondition_failed;
+use crate::routes_get::handle_snapshots;
+use crate::routes_get::handle_users;
+use crate::serializables::AppState;
 use crate::serializables::Claims;
+use crate::serializables::DataType;
 use crate::serializables::ResponseBodyGeneric;
+use crate::serializables::Role;
 use crate::state::ApiKey;
+use crate::state::PendingCommits;
+use crate::state::Revocations;
 use crate::state::ZKConfig;
 use crate::tokens::issue_token;
+use crate::tokens::ACCESS_TOKEN_MINUTES;
+use crate::tokens::REFRESH_TOKEN_DAYS;
+use crate::trash;
+use crate::users::UserStore;
+use chrono::Duration;
+use git2::ErrorCode;
+use git2::Reference;
+use rocket::form::Form;
 use rocket::http::Cookie;
 use rocket::http::CookieJar;
+use rocket::serde::json::serde_json::json;
 use rocket::serde::json::Json;
+use rocket::tokio::task;
 use rocket::State;
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::io;
+use std::path::Path;
 use std::path::PathBuf;
+use std::slice;
 
 // All routes mounted at api base Path
 
 #[post("/?auth", format = "json", data = "<message>")]
-pub(crate) fn auth_index<'a>(
+pub(crate) fn auth_index(
     message: Json<AuthAttempt>,
     csrf: Result<CSRFClaims, AuthError>,
-    cookies: &'a CookieJar,
+    cookies: &CookieJar,
     apikey: &State<ApiKey>,
     consts: &State<ZKConfig>,
+    users: &State<UserStore>,
 ) -> ApiResponse {
-    auth("./".into(), message, csrf, cookies, apikey, consts)
+    auth("./".into(), message, csrf, cookies, apikey, consts, users)
 }
 
 #[post("/<path..>?auth", format = "json", data = "<message>")]
-pub(crate) fn auth<'a>(
+pub(crate) fn auth(
     path: PathBuf,
     message: Json<AuthAttempt>,
     csrf: Result<CSRFClaims, AuthError>,
-    cookies: &'a CookieJar,
+    cookies: &CookieJar,
     apikey: &State<ApiKey>,
     consts: &State<ZKConfig>,
+    users: &State<UserStore>,
 ) -> ApiResponse {
     if csrf.is_err() {
         return handle_jwt_error(path, consts, apikey, &csrf.err().unwrap());
     }
-    let absolutepath = PathBuf::from(consts.repo_files_location.clone() + &message.username);
-    if !absolutepath.exists() || message.password != consts.admin_password {
+    if !users.verify(&message.username, &message.password) {
         return handle_jwt_error(path, consts, apikey, &AuthError::WrongUsernamePassword);
     }
-    let claims = Claims::default()
+    let claims = session_claims(&path, &message.username, consts, users);
+    if let Err(e) = users.record_login(&message.username) {
+        return handle_io_error(path, claims, apikey, e);
+    }
+    // Initializes the repository on the first login
+    if let Err(e) = create_dir_all(user_basepath(consts, &claims)) {
+        return handle_io_error(path, claims, apikey, e);
+    }
+    if let Err(e) = user_repository(consts, &claims) {
+        return handle_git_error(path, claims, apikey, e);
+    }
+    start_session(path, claims, cookies, apikey, consts)
+}
+
+// Replaces the refresh token with a new one of the same session, and issues a new access token. A
+// refresh token can only be used once: using it again ends the session.
+#[post("/?refresh", rank = 14)]
+pub(crate) fn refresh(
+    csrf: Result<CSRFClaims, AuthError>,
+    refresh: Result<RefreshClaims, AuthError>,
+    cookies: &CookieJar,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    users: &State<UserStore>,
+    revocations: &State<Revocations>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let refresh = refresh.map(|r| r.0);
+    if let Some(e) = check_claims_csrf(&refresh, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let refresh = refresh.unwrap();
+    let claims =
+        session_claims(&path, &refresh.get_sub(), consts, users).set_sid(&refresh.get_sid());
+    match revocations.revoke(&refresh) {
+        Ok(false) => start_session(path, claims, cookies, apikey, consts),
+        Ok(true) => handle_jwt_error(path, consts, apikey, &AuthError::Revoked),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Ends the session: all its access and refresh tokens are revoked, and the cookies removed
+#[post("/?logout", rank = 15)]
+pub(crate) fn logout(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    refresh: Result<RefreshClaims, AuthError>,
+    cookies: &CookieJar,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    revocations: &State<Revocations>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Err(e) = csrf {
+        return handle_jwt_error(path, consts, apikey, &e);
+    }
+    let anonymous = Claims::default().set_iss(consts.hostname.as_str());
+    for c in claims.iter().chain(refresh.iter().map(|r| &r.0)) {
+        if let Err(e) = revocations.end_session(c) {
+            return handle_io_error(path, anonymous, apikey, e);
+        }
+    }
+    cookies.remove_private(Cookie::named("jwt"));
+    cookies.remove_private(Cookie::named("refresh"));
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), apikey, &anonymous)
+        .set_appstate(AppState::default().set_authorized(false));
+    ApiResponse::ok(res).set_header("Clear-Site-Data", r#""*""#)
+}
+
+// Claims of a new session of `user`. The role is taken from the account, so changes to it apply
+// once the access token is refreshed.
+fn session_claims(path: &Path, user: &str, consts: &ZKConfig, users: &UserStore) -> Claims {
+    let role = match users.get(user) {
+        Some(u) if u.admin => Role::Admin,
+        _ => Role::User,
+    };
+    Claims::default()
         .set_iss(consts.hostname.as_str())
-        .set_sub(message.username.as_str())
-        .set_aud(path.to_str().unwrap_or_default());
-    cookies.add_private(Cookie::new(
-        "jwt",
-        issue_token(&claims, apikey.inner()).unwrap(),
-    ));
-    return api(APIPath(path), Ok(claims), consts, apikey);
+        .set_sub(user)
+        .set_aud(path.to_str().unwrap_or_default())
+        .set_role(role)
+        .set_iat_exp_nbf(Duration::minutes(ACCESS_TOKEN_MINUTES))
 }
 
-#[allow(unused)] // TODO: Implement creation
-#[post("/?new", format = "json", data = "<message>")]
+// Sets the access token and a new refresh token as private cookies
+fn start_session(
+    path: PathBuf,
+    claims: Claims,
+    cookies: &CookieJar,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let refresh = Claims::default()
+        .set_iss(consts.hostname.as_str())
+        .set_sub(&claims.get_sub())
+        .set_sid(&claims.get_sid())
+        .set_refresh(true)
+        .set_iat_exp_nbf(Duration::days(REFRESH_TOKEN_DAYS));
+    // The tokens expire long before the cookies
+    for (name, claims) in [("jwt", &claims), ("refresh", &refresh)] {
+        let mut cookie = Cookie::new(name, issue_token(claims, apikey.inner()).unwrap());
+        cookie.make_permanent();
+        cookies.add_private(cookie);
+    }
+    api(APIPath(path), Ok(claims), consts, apikey)
+}
+
+#[post("/?new", format = "json", data = "<message>", rank = 2)]
 pub(crate) fn create_index(
     csrf: Result<CSRFClaims, AuthError>,
     claims: Result<Claims, AuthError>,
@@ -70,8 +220,7 @@ pub(crate) fn create_index(
     create("./".into(), csrf, claims, message, apikey, consts)
 }
 
-#[allow(unused)] // TODO: Implement creation
-#[post("/<path..>?new", format = "json", data = "<message>")]
+#[post("/<path..>?new", format = "json", data = "<message>", rank = 1)]
 pub(crate) fn create(
     path: PathBuf,
     csrf: Result<CSRFClaims, AuthError>,
@@ -81,8 +230,617 @@ pub(crate) fn create(
     consts: &State<ZKConfig>,
 ) -> ApiResponse {
     if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
-        handle_jwt_error(path, consts, apikey, e)
-    } else {
-        ApiResponse::ok(ResponseBodyGeneric::default())
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path, &basepath) {
+        Some(e) if matches!(e.ftype, FType::Directory) => {
+            handle_create(path, message.into_inner(), claims, apikey, consts, basepath)
+        }
+        _ => handle_invalid_path(path, claims, apikey),
+    }
+}
+
+fn handle_create(
+    path: PathBuf,
+    message: CreateAttempt,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let added =
+        match filesystem_interact::create(&path, &basepath, &message.options, &message.ftype) {
+            Ok(p) => p,
+            Err(e) => return handle_io_error(path, claims, key, e),
+        };
+    let url = match message.ftype {
+        FType::Directory => added.parent().unwrap_or(&path).to_path_buf(),
+        _ => added.clone(),
+    };
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.add_and_commit(&added, &format!("Create {}", url.display()))?;
+        r.find_commit(oid)
+    });
+    match (commit, open(&url, &basepath)) {
+        (Err(e), _) => handle_git_error(url, claims, key, e),
+        (Ok(c), Some(e)) => handle_entry(url, e, claims, key, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(url, claims, key),
+    }
+}
+
+#[post(
+    "/?upload",
+    format = "multipart/form-data",
+    data = "<message>",
+    rank = 5
+)]
+pub(crate) async fn upload_index(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    message: Form<UploadAttempt<'_>>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    upload("./".into(), csrf, claims, message, apikey, consts).await
+}
+
+#[post(
+    "/<path..>?upload",
+    format = "multipart/form-data",
+    data = "<message>",
+    rank = 4
+)]
+pub(crate) async fn upload(
+    path: PathBuf,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    message: Form<UploadAttempt<'_>>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path, &basepath) {
+        Some(e) if matches!(e.ftype, FType::Directory) => {
+            handle_upload(path, message.into_inner(), claims, apikey, consts, basepath).await
+        }
+        _ => handle_invalid_path(path, claims, apikey),
+    }
+}
+
+async fn handle_upload(
+    path: PathBuf,
+    mut message: UploadAttempt<'_>,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let name = message.name.clone().or_else(|| {
+        message
+            .file
+            .raw_name()
+            .map(|n| n.dangerous_unsafe_unsanitized_raw().to_string())
+    });
+    let url = match attachment_path(&path, &basepath, &name.unwrap_or_default()) {
+        Ok(u) => u,
+        Err(e) => return handle_io_error(path, claims, key, e),
+    };
+    if let Err(e) = message.file.move_copy_to(basepath.join(&url)).await {
+        return handle_io_error(path, claims, key, e);
+    }
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.add_and_commit(&url, &format!("Upload {}", url.display()))?;
+        r.find_commit(oid)
+    });
+    match (commit, open(&url, &basepath)) {
+        (Err(e), _) => handle_git_error(url, claims, key, e),
+        (Ok(c), Some(e)) => handle_entry(url, e, claims, key, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(url, claims, key),
+    }
+}
+
+#[post("/<path..>?move", format = "json", data = "<message>", rank = 3)]
+pub(crate) fn move_entry(
+    path: PathBuf,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    if_match: IfMatch,
+    message: Json<MoveAttempt>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path, &basepath) {
+        Some(e) if !if_match.matches(&e) => {
+            handle_precondition_failed(path, e, claims, apikey, basepath)
+        }
+        Some(e) => handle_move(path, e, &message.target, claims, apikey, consts, basepath),
+        None => handle_invalid_path(path, claims, apikey),
+    }
+}
+
+fn handle_move(
+    path: PathBuf,
+    entry: Entry,
+    target: &str,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let old_files = list_files(&path, &basepath, true);
+    let target = match filesystem_interact::move_entry(&entry, target, &basepath) {
+        Ok(t) => t,
+        Err(e) => return handle_io_error(path, claims, key, e),
+    };
+    let moves: HashMap<PathBuf, PathBuf> = old_files
+        .iter()
+        .map(|f| match entry.ftype {
+            FType::Directory => (f.clone(), target.join(f.strip_prefix(&path).unwrap_or(f))),
+            _ => (f.clone(), target.clone()),
+        })
+        .collect();
+    let new_of_old: HashMap<&PathBuf, &PathBuf> = moves.iter().map(|(o, n)| (n, o)).collect();
+    let mut changed: Vec<PathBuf> = moves.values().cloned().collect();
+    for note in list_files(Path::new(""), &basepath, false)
+        .into_iter()
+        .filter(|f| f.extension().unwrap_or_default() == "md")
+    {
+        let old = new_of_old
+            .get(&note)
+            .map_or(note.as_path(), |o| o.as_path());
+        let content = match read_to_string(basepath.join(&note)) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        if let Some(c) = rewrite_links(&content, old, &note, &moves) {
+            if let Err(e) = write(basepath.join(&note), c) {
+                return handle_io_error(note, claims, key, e);
+            }
+            if !changed.contains(&note) {
+                changed.push(note);
+            }
+        }
+    }
+    let updated: Vec<String> = changed
+        .iter()
+        .filter(|c| !moves.values().any(|n| &n == c))
+        .map(|c| c.to_string_lossy().to_string())
+        .collect();
+    let message = match updated.is_empty() {
+        true => format!("Move {} to {}", path.display(), target.display()),
+        false => format!(
+            "Move {} to {}\n\nUpdated links in:\n{}",
+            path.display(),
+            target.display(),
+            updated.join("\n")
+        ),
+    };
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.commit_paths(&changed, slice::from_ref(&path), &message)?;
+        r.find_commit(oid)
+    });
+    match (commit, open(&target, &basepath)) {
+        (Err(e), _) => handle_git_error(target, claims, key, e),
+        (Ok(c), Some(e)) => handle_entry(target, e, claims, key, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(target, claims, key),
+    }
+}
+
+// Restores an item from the trash, to its original path unless another target is given
+#[post("/?restore", format = "json", data = "<message>", rank = 6)]
+pub(crate) fn restore(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    message: Json<RestoreAttempt>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let target = match &message.target {
+        Some(t) => match normalize(Path::new(t.trim_start_matches('/')))
+            .filter(|t| t.components().next().is_some() && !is_hidden_path(t))
+        {
+            Some(t) => Some(t),
+            None => return handle_invalid_path(PathBuf::from(t), claims, apikey),
+        },
+        None => None,
+    };
+    let (item, target) = match trash::restore(&message.id, target, &basepath) {
+        Ok(r) => r,
+        Err(e) => return handle_io_error(path, claims, apikey, e),
+    };
+    let mut added = list_files(&target, &basepath, true);
+    added.push(trash::index_path());
+    let message = format!("Restore {} to {}", item.path.display(), target.display());
+    let commit = user_repository(consts, &claims).and_then(|mut r| {
+        let oid = r.commit_paths(&added, &[item.trash_dir()], &message)?;
+        r.find_commit(oid)
+    });
+    match (commit, open(&target, &basepath)) {
+        (Err(e), _) => handle_git_error(target, claims, apikey, e),
+        (Ok(c), Some(e)) => handle_entry(target, e, claims, apikey, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(target, claims, apikey),
+    }
+}
+
+// Restores the content a file had in an earlier commit, as a new commit
+#[post("/<path..>?<revert>", rank = 7)]
+pub(crate) fn revert(
+    path: PathBuf,
+    revert: String,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    if_match: IfMatch,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    match open(&path, &basepath) {
+        Some(e) if matches!(e.ftype, FType::Directory) => handle_invalid_path(path, claims, apikey),
+        Some(e) if !if_match.matches(&e) => {
+            handle_precondition_failed(path, e, claims, apikey, basepath)
+        }
+        _ => handle_revert(path, &revert, claims, apikey, consts, basepath),
+    }
+}
+
+fn handle_revert(
+    path: PathBuf,
+    rev: &str,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let mut repo = match user_repository(consts, &claims) {
+        Ok(r) => r,
+        Err(e) => return handle_git_error(path, claims, key, e),
+    };
+    let (revision, content) = match repo.file_at(&path, rev) {
+        Ok(f) => f,
+        Err(e) if e.code() == ErrorCode::NotFound => return handle_invalid_path(path, claims, key),
+        Err(e) => return handle_git_error(path, claims, key, e),
+    };
+    if let Err(e) = write(basepath.join(&path), content) {
+        return handle_io_error(path, claims, key, e);
+    }
+    let message = format!(
+        "Revert {} to {}\n\nRestores the content from commit {}.",
+        path.display(),
+        &revision.id[..7],
+        revision.id
+    );
+    let commit = repo
+        .add_and_commit(&path, &message)
+        .and_then(|oid| repo.find_commit(oid));
+    match (commit, open(&path, &basepath)) {
+        (Err(e), _) => handle_git_error(path, claims, key, e),
+        (Ok(c), Some(e)) => handle_entry(path, e, claims, key, basepath, Some(c)),
+        (Ok(_), None) => handle_invalid_path(path, claims, key),
+    }
+}
+
+// Fetches from the remote, fast-forwards or merges, and pushes the result. The network is only used
+// from a blocking thread, so a slow remote doesn't hold up other requests.
+#[post("/?sync", rank = 8)]
+pub(crate) async fn sync(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let (config, user) = (consts.inner().clone(), claims.clone());
+    let result = task::spawn_blocking(move || {
+        let mut repo = user_repository(&config, &user)?;
+        let error = match repo.remote_url()? {
+            Some(u) if is_remote_url(&u) => None,
+            Some(_) => Some(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Remote is not supported.",
+            )),
+            None => Some(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No remote configured.",
+            )),
+        };
+        match error {
+            Some(e) => Ok(Err(e)),
+            None => Ok(Ok((repo.sync()?, repo.find_last_commit().ok()))),
+        }
+    })
+    .await;
+    let (outcome, commit) = match result {
+        Ok(Ok(Ok(r))) => r,
+        Ok(Ok(Err(e))) => return handle_io_error(path, claims, apikey, e),
+        Ok(Err(e)) => return handle_git_error(path, claims, apikey, e),
+        Err(e) => return handle_io_error(path, claims, apikey, io::Error::other(e)),
+    };
+    let res = ResponseBodyGeneric::default().set_apiurl(
+        path.to_str().unwrap_or_default(),
+        apikey,
+        &claims,
+    );
+    match outcome {
+        SyncOutcome::Conflict(paths) => ApiResponse::conflict(
+            res.set_inner(
+                json!({"message": "Changes on the remote conflict with local changes.", "paths": paths}),
+                DataType::MergeConflict,
+            )
+            .set_appstate(AppState::default().set_authorized(true).set_commit(commit)),
+        ),
+        SyncOutcome::Refused(reason) => ApiResponse::conflict(
+            res.set_inner(json!({ "message": reason }), DataType::ErrorMessage)
+                .set_appstate(AppState::default().set_authorized(true).set_commit(commit)),
+        ),
+        o => ApiResponse::ok(
+            res.set_inner(json!({ "outcome": o }), DataType::Sync)
+                .set_appstate(AppState::default().set_authorized(true).set_commit(commit)),
+        ),
+    }
+}
+
+// Commits all pending changes right away, instead of waiting for the user to stop editing
+#[post("/?commit", format = "json", data = "<message>", rank = 9)]
+pub(crate) fn commit(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    message: Json<CommitAttempt>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let subject = message
+        .message
+        .as_deref()
+        .map(str::trim)
+        .filter(|m| !m.is_empty());
+    let commit = commit_pending(consts, pending, &claims.get_sub(), subject);
+    match (commit, open(&path, &basepath)) {
+        (Err(e), _) => handle_git_error(path, claims, apikey, e),
+        (Ok(c), Some(e)) => handle_entry(path, e, claims, apikey, basepath, c),
+        (Ok(_), None) => handle_invalid_path(path, claims, apikey),
+    }
+}
+
+// Creates a snapshot of the whole repository, as an annotated tag of the last commit. Pending
+// changes are committed first, so the snapshot matches what the user sees.
+#[post("/?snapshot", format = "json", data = "<snapshot>", rank = 10)]
+pub(crate) fn snapshot(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    snapshot: Json<SnapshotAttempt>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    let name = snapshot.name.trim();
+    if !Reference::is_valid_name(&format!("refs/tags/{}", name)) {
+        return handle_io_error(
+            path,
+            claims,
+            apikey,
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid snapshot name."),
+        );
+    }
+    if let Err(e) = commit_pending(consts, pending, &claims.get_sub(), None) {
+        return handle_git_error(path, claims, apikey, e);
+    }
+    let message = snapshot.message.as_deref().unwrap_or(name);
+    match user_repository(consts, &claims).and_then(|r| r.create_snapshot(name, message)) {
+        Ok(s) => handle_snapshots(path, claims, apikey, basepath, Some(s.commit)),
+        Err(e) if e.code() == ErrorCode::Exists => handle_io_error(
+            path,
+            claims,
+            apikey,
+            io::Error::new(io::ErrorKind::AlreadyExists, "Snapshot already exists."),
+        ),
+        Err(e) => handle_git_error(path, claims, apikey, e),
+    }
+}
+
+// Creates an account and initializes its repository. Admins only.
+#[post("/?user", format = "json", data = "<message>", rank = 12)]
+pub(crate) fn create_user(
+    message: Json<UserAttempt>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    users: &State<UserStore>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    // The name is used as directory name
+    let name = message.name.trim();
+    let valid =
+        filesystem_interact::valid_name(name).and_then(|_| match message.password.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Password must not be empty.",
+            )),
+            false => Ok(()),
+        });
+    let user = Claims::default().set_sub(name);
+    // The repository is set up first, so there is no account without one
+    let prepared = valid
+        .and_then(|_| match users.get(name) {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "User already exists.",
+            )),
+            None => Ok(()),
+        })
+        .and_then(|_| create_dir_all(user_basepath(consts, &user)));
+    if let Err(e) = prepared {
+        return handle_io_error(path, claims, apikey, e);
+    }
+    if let Err(e) = user_repository(consts, &user) {
+        return handle_git_error(path, claims, apikey, e);
+    }
+    match users.create(name, &message.password, message.admin) {
+        Ok(_) => handle_users(path, claims, apikey, users),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Adds a new key to sign tokens with. Tokens signed with the old keys stay valid until they are
+// retired. Admins only.
+#[post("/?keys", rank = 13)]
+pub(crate) fn rotate_keys(
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    match apikey.rotate() {
+        Ok(_) => handle_keys(path, claims, apikey),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes_get::api_index;
+    use crate::state::ApiKey;
+    use crate::state::PendingCommits;
+    use crate::state::Revocations;
+    use crate::state::ZKConfig;
+    use crate::users::UserStore;
+    use figment::providers::Format;
+    use figment::providers::Toml;
+    use figment::Figment;
+    use rocket::http::Accept;
+    use rocket::http::ContentType;
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::serde_json;
+    use rocket::serde::json::Value;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    fn client(base: &Path) -> Client {
+        let notes = base.join("notes");
+        create_dir_all(notes.join("alice")).unwrap();
+        let config: ZKConfig = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                path = "/api"
+                cors = false
+                repo_files_location = "{}"
+                hostname = "localhost"
+                admin_password = "secret"
+                "#,
+                notes.display()
+            )))
+            .extract()
+            .unwrap();
+        let rocket = rocket::build()
+            .manage(UserStore::open(&config).unwrap())
+            .manage(ApiKey::open(&config).unwrap())
+            .manage(Revocations::open(&config).unwrap())
+            .manage(PendingCommits::default())
+            .manage(config)
+            .mount(
+                "/api",
+                routes![api_index, super::auth_index, super::refresh, super::logout],
+            );
+        Client::tracked(rocket).unwrap()
+    }
+
+    // Every response carries a token to send as CSRF token with the next request
+    fn csrf(client: &Client) -> Header<'static> {
+        let response = client.get("/api/").header(Accept::JSON).dispatch();
+        let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        Header::new("XSRF-TOKEN", body["token"].as_str().unwrap().to_string())
+    }
+
+    fn status(client: &Client, path: &str) -> Status {
+        let request = match path {
+            "/api/" => client.get(path),
+            _ => client.post(path).header(csrf(client)),
+        };
+        request.header(Accept::JSON).dispatch().status()
+    }
+
+    #[test]
+    fn refresh_token_reuse_ends_session() {
+        let base: PathBuf = env::temp_dir().join(format!("zk-refresh-{}", std::process::id()));
+        let client = client(&base);
+        let response = client
+            .post("/api/?auth")
+            .header(csrf(&client))
+            .header(ContentType::JSON)
+            .header(Accept::JSON)
+            .body(r#"{"username":"alice","password":"secret"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let stolen = client.cookies().get_private("refresh").unwrap();
+
+        assert_eq!(status(&client, "/api/?refresh"), Status::Ok);
+        assert_eq!(status(&client, "/api/"), Status::Ok);
+
+        // The used refresh token ends the session it belongs to, including the newer tokens
+        let response = client
+            .post("/api/?refresh")
+            .header(csrf(&client))
+            .header(Accept::JSON)
+            .private_cookie(stolen)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(status(&client, "/api/"), Status::Unauthorized);
+        assert_eq!(status(&client, "/api/?refresh"), Status::Unauthorized);
+
+        remove_dir_all(&base).ok();
     }
 }
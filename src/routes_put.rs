@@ -0,0 +1,287 @@
+use crate::deserializables::ProfileAttempt;
+use crate::deserializables::RemoteAttempt;
+use crate::deserializables::UserUpdateAttempt;
+use crate::filesystem_interact::is_hidden_path;
+use crate::filesystem_interact::open;
+use crate::filesystem_interact::Entry;
+use crate::filesystem_interact::FType;
+use crate::functions::check_claims_csrf;
+use crate::functions::commit_or_defer;
+use crate::functions::handle_git_error;
+use crate::functions::handle_io_error;
+use crate::functions::handle_jwt_error;
+use crate::functions::is_remote_url;
+use crate::functions::user_basepath;
+use crate::functions::user_repository;
+use crate::git_interact::lock_index;
+use crate::git_interact::precondition_lock;
+use crate::git_interact::ConflictHunk;
+use crate::git_interact::MergeOutcome;
+use crate::git_interact::Profile;
+use crate::git_interact::RemoteCredentials;
+use crate::requestguards::APIPath;
+use crate::requestguards::AdminClaims;
+use crate::requestguards::AuthError;
+use crate::requestguards::CSRFClaims;
+use crate::requestguards::IfMatch;
+use crate::responders::ApiResponse;
+use crate::routes_get::handle_invalid_path;
+use crate::routes_get::handle_markdown_file;
+use crate::routes_get::handle_precondition_failed;
+use crate::routes_get::handle_profile;
+use crate::routes_get::handle_remote;
+use crate::routes_get::handle_users;
+use crate::serializables::AppState;
+use crate::serializables::Claims;
+use crate::serializables::DataType;
+use crate::serializables::ResponseBodyGeneric;
+use crate::state::ApiKey;
+use crate::state::PendingCommits;
+use crate::state::ZKConfig;
+use crate::users::UserStore;
+use git2::Signature;
+use rocket::data::ByteUnit;
+use rocket::data::Data;
+use rocket::data::Limits;
+use rocket::data::ToByteUnit;
+use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::io;
+use std::path::PathBuf;
+
+// All routes mounted at API base path
+// Sets the url of the remote the users repository is synced with, and the credentials for it
+#[put("/?remote", format = "json", data = "<message>", rank = 2)]
+pub(crate) fn remote(
+    message: Json<RemoteAttempt>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let message = message.into_inner();
+    let url = message
+        .url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty());
+    if !url.is_none_or(is_remote_url) {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only https:// and ssh:// remotes are supported.",
+        );
+        return handle_io_error(path, claims, apikey, e);
+    }
+    let credentials = RemoteCredentials {
+        username: message.username.filter(|u| !u.is_empty()),
+        password: message.password.filter(|p| !p.is_empty()),
+        ssh_key: message.ssh_key.filter(|k| !k.trim().is_empty()),
+    };
+    let result = user_repository(consts, &claims).and_then(|r| {
+        r.set_remote_url(url)?;
+        match url {
+            Some(_) => r.set_remote_credentials(&credentials),
+            None => Ok(()),
+        }
+    });
+    match result {
+        Ok(_) => handle_remote(path, claims, apikey, consts),
+        Err(e) => handle_git_error(path, claims, apikey, e),
+    }
+}
+
+// Name and email the users commits are authored with. Missing values fall back to the username and
+// the servers hostname.
+#[put("/?profile", format = "json", data = "<message>", rank = 1)]
+pub(crate) fn profile(
+    message: Json<ProfileAttempt>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let profile = Profile {
+        name: message.name.clone().filter(|n| !n.trim().is_empty()),
+        email: message.email.clone().filter(|e| !e.trim().is_empty()),
+    };
+    // Names and emails git can't store are rejected
+    if Signature::now(
+        profile.name.as_deref().unwrap_or("name"),
+        profile.email.as_deref().unwrap_or("email"),
+    )
+    .is_err()
+    {
+        return handle_io_error(
+            path,
+            claims,
+            apikey,
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid name or email."),
+        );
+    }
+    match user_repository(consts, &claims).and_then(|r| r.set_profile(&profile)) {
+        Ok(_) => handle_profile(path, claims, apikey, consts),
+        Err(e) => handle_git_error(path, claims, apikey, e),
+    }
+}
+
+// Resets the password of an account, or disables or enables it, or grants or revokes admin rights.
+// Admins only, and they can't lock themselves out.
+#[put("/?<user>", format = "json", data = "<message>", rank = 3)]
+pub(crate) fn user(
+    user: String,
+    message: Json<UserUpdateAttempt>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<AdminClaims, AuthError>,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    users: &State<UserStore>,
+) -> ApiResponse {
+    let path = PathBuf::from("./");
+    let claims = claims.map(|c| c.0);
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let invalid = match (&message.password, message.disabled, message.admin) {
+        (Some(p), _, _) if p.is_empty() => Some("Password must not be empty."),
+        (_, Some(true), _) | (_, _, Some(false)) if user == claims.get_sub() => {
+            Some("You can't lock yourself out.")
+        }
+        _ => None,
+    };
+    if let Some(m) = invalid {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, m);
+        return handle_io_error(path, claims, apikey, e);
+    }
+    match users.update(
+        &user,
+        message.password.as_deref(),
+        message.disabled,
+        message.admin,
+    ) {
+        Ok(_) => handle_users(path, claims, apikey, users),
+        Err(e) => handle_io_error(path, claims, apikey, e),
+    }
+}
+
+// Size of saved notes, unless set with `limits.note`. Rocket's default string limit is too small.
+fn note_limit(limits: &Limits) -> ByteUnit {
+    limits.get("note").unwrap_or_else(|| 10.mebibytes())
+}
+
+// If base is set to the commit the client started editing from, concurrent changes to the note are
+// merged with the submitted content. If commits are delayed, base lags behind the saved content,
+// so clients should rely on If-Match instead.
+#[put("/<path..>?<base>", data = "<content>", rank = 4)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn save(
+    path: APIPath,
+    base: Option<String>,
+    content: Data<'_>,
+    csrf: Result<CSRFClaims, AuthError>,
+    claims: Result<Claims, AuthError>,
+    if_match: IfMatch,
+    limits: &Limits,
+    apikey: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+) -> ApiResponse {
+    if let Some(e) = check_claims_csrf(&claims, Some(&csrf)) {
+        return handle_jwt_error(path.0, consts, apikey, e);
+    }
+    let claims = claims.unwrap();
+    let basepath = user_basepath(consts, &claims);
+    if is_hidden_path(&path.0) {
+        return handle_invalid_path(path.0, claims, apikey);
+    }
+    let content = match content.open(note_limit(limits)).into_string().await {
+        Ok(c) if c.is_complete() => c.into_inner(),
+        Ok(_) => {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "Note is too large.");
+            return handle_io_error(path.0, claims, apikey, e);
+        }
+        Err(e) => return handle_io_error(path.0, claims, apikey, e),
+    };
+    let lock = precondition_lock(&basepath);
+    let _guard = lock_index(&lock);
+    match open(&path.0, &basepath) {
+        Some(e) if matches!(e.ftype, FType::MDFile) && !if_match.matches(&e) => {
+            handle_precondition_failed(path.0, e, claims, apikey, basepath)
+        }
+        Some(e) if matches!(e.ftype, FType::MDFile) => handle_save(
+            path.0, e, content, base, claims, apikey, consts, pending, basepath,
+        ),
+        _ => handle_invalid_path(path.0, claims, apikey),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_save(
+    path: PathBuf,
+    mdfile: Entry,
+    content: String,
+    base: Option<String>,
+    claims: Claims,
+    key: &State<ApiKey>,
+    consts: &State<ZKConfig>,
+    pending: &State<PendingCommits>,
+    basepath: PathBuf,
+) -> ApiResponse {
+    let mut repo = match user_repository(consts, &claims) {
+        Ok(r) => r,
+        Err(e) => return handle_git_error(path, claims, key, e),
+    };
+    let (content, message) = match base {
+        None => (content, format!("Update {}", path.display())),
+        Some(base) => {
+            let current = match read_to_string(&mdfile.data) {
+                Ok(c) => c,
+                Err(e) => return handle_io_error(path, claims, key, e),
+            };
+            match repo.merge_file(&path, &base, &current, &content) {
+                Ok(MergeOutcome::Clean(c)) => (c, format!("Update {} (merged)", path.display())),
+                Ok(MergeOutcome::Conflict(hunks)) => {
+                    return handle_merge_conflict(path, mdfile, hunks, claims, key)
+                }
+                Err(e) => return handle_git_error(path, claims, key, e),
+            }
+        }
+    };
+    if let Err(e) = write(&mdfile.data, content) {
+        return handle_io_error(path, claims, key, e);
+    }
+    match commit_or_defer(&mut repo, &path, &message, consts, pending, &claims) {
+        Ok(c) => handle_markdown_file(path, mdfile, claims, key, basepath, c),
+        Err(e) => handle_git_error(path, claims, key, e),
+    }
+}
+
+fn handle_merge_conflict(
+    path: PathBuf,
+    mdfile: Entry,
+    hunks: Vec<ConflictHunk>,
+    claims: Claims,
+    key: &State<ApiKey>,
+) -> ApiResponse {
+    let res = ResponseBodyGeneric::default()
+        .set_apiurl(path.to_str().unwrap_or_default(), key, &claims)
+        .set_inner(
+            json!({"message": "Note was changed concurrently and could not be merged.", "hunks": hunks, "current": mdfile.json()}),
+            DataType::MergeConflict,
+        )
+        .set_appstate(AppState::default().set_authorized(true));
+    ApiResponse::conflict(res)
+}
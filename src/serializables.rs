@@ -8,6 +8,7 @@ use chrono::Local;
 use chrono::Timelike;
 use chrono::Utc;
 use crypto_hashes::sha2::{Digest, Sha256};
+use rand::Rng;
 use rocket::serde::json::serde_json::json;
 use rocket::serde::json::Value;
 use serde::Serialize;
@@ -23,6 +24,22 @@ pub(crate) struct Claims {
     nbf: DateTime<Utc>, // Optional. When the Key starts working.
     sub: String, // Optional. Subject (whom token refers to)
     aud: String, // Optional. Identfies the Subject further (constructed and verified in header)
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    jti: String, // Id of the token. Refresh tokens are revoked once used.
+    #[serde(default)]
+    sid: String, // Id of the session, kept across refreshes. Revoked on logout.
+    #[serde(default)]
+    refresh: bool, // Refresh tokens only grant new tokens
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    #[default]
+    User,
+    Admin,
 }
 
 impl Default for Claims {
@@ -46,6 +63,10 @@ impl Default for Claims {
             iss: String::default(),
             aud: String::default(),
             sub: String::default(),
+            role: Role::default(),
+            jti: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            sid: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            refresh: false,
         }
     }
 }
@@ -61,15 +82,30 @@ impl Claims {
         self
     }
 
+    pub(crate) fn set_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
     pub(crate) fn set_iss(mut self, iss: &str) -> Self {
         self.iss = iss.to_string();
         self
     }
 
-    pub(crate) fn set_iat_exp_nbf(mut self, duration: i64) -> Self {
+    pub(crate) fn set_sid(mut self, sid: &str) -> Self {
+        self.sid = sid.to_string();
+        self
+    }
+
+    pub(crate) fn set_refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub(crate) fn set_iat_exp_nbf(mut self, duration: Duration) -> Self {
         let iat = Utc::now();
         let nbf = Utc::now();
-        let exp = iat + Duration::hours(duration);
+        let exp = iat + duration;
         let iat = iat
             .date()
             .and_hms_milli(iat.hour(), iat.minute(), iat.second(), 0);
@@ -89,6 +125,34 @@ impl Claims {
         self.sub.clone()
     }
 
+    pub(crate) fn get_jti(&self) -> String {
+        self.jti.clone()
+    }
+
+    // Tokens issued before sessions had ids are their own session
+    pub(crate) fn get_sid(&self) -> String {
+        match self.sid.is_empty() {
+            true => self.jti.clone(),
+            false => self.sid.clone(),
+        }
+    }
+
+    pub(crate) fn get_iat(&self) -> i64 {
+        self.iat.timestamp()
+    }
+
+    pub(crate) fn get_exp(&self) -> i64 {
+        self.exp.timestamp()
+    }
+
+    pub(crate) fn is_refresh(&self) -> bool {
+        self.refresh
+    }
+
+    pub(crate) fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
     #[allow(unused)] //  TODO check for right aud on edits
     pub(crate) fn get_aud(&self) -> String {
         self.aud.clone()
@@ -101,13 +165,26 @@ pub(crate) enum DataType {
     ErrorMessage,
     MD,
     Directory,
+    Attachment,
+    MergeConflict,
+    Trash,
+    History,
+    Diff,
+    Blame,
+    Profile,
+    Remote,
+    Sync,
+    Snapshots,
+    Users,
+    Keys,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct AppState {
     authorized: bool,
     time: String,
-    commit: Option<CommitData>,
+    commit: Option<CommitData>,      // Last commit of the repository
+    path_commit: Option<CommitData>, // Last commit that changed the requested file or directory
 }
 
 impl Default for AppState {
@@ -115,6 +192,7 @@ impl Default for AppState {
         Self {
             authorized: false,
             commit: None,
+            path_commit: None,
             time: Local::now().to_rfc2822(),
         }
     }
@@ -126,11 +204,15 @@ impl AppState {
         self
     }
 
-    #[allow(unused)] // TODO Git Integration
     pub(crate) fn set_commit(mut self, commit: Option<CommitData>) -> Self {
         self.commit = commit;
         self
     }
+
+    pub(crate) fn set_path_commit(mut self, commit: Option<CommitData>) -> Self {
+        self.path_commit = commit;
+        self
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -190,7 +272,11 @@ impl ResponseBodyGeneric {
 
     fn set_token(mut self, apiurl: &str, key: &ApiKey, claims: &Claims) -> Self {
         self.apiurl = apiurl.to_string();
-        self.token = issue_token(&(claims.clone().set_aud(apiurl).set_iat_exp_nbf(12)), key).ok();
+        let claims = claims
+            .clone()
+            .set_aud(apiurl)
+            .set_iat_exp_nbf(Duration::hours(12));
+        self.token = issue_token(&claims, key).ok();
         self
     }
 
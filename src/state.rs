@@ -1,14 +1,200 @@
-use crypto_hashes::sha2::Sha256;
-use hmac::crypto_mac::Output;
-use hmac::Hmac;
+use crate::serializables::Claims;
+use crate::tokens::LEEWAY;
+use crate::tokens::REFRESH_TOKEN_DAYS;
+use chrono::Local;
+use chrono::Utc;
+use rand::Rng;
+use rocket::serde::json::serde_json;
+use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Value;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::write;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
 
-type HmacSha256 = Output<Hmac<Sha256>>;
+const KEYS_FILE: &str = "jwt_keys.json";
+const REVOKED_FILE: &str = "revoked_tokens.json";
+const COOKIE_KEY_FILE: &str = "cookie_key";
 
-pub(crate) struct ApiKey(pub(crate) HmacSha256);
+// Keys tokens are signed with. The newest key that is not retired signs new tokens, and tokens
+// signed with any key that is not retired are accepted. So keys can be rotated without ending any
+// session: add a new key, and retire the old one once its tokens expired.
+pub(crate) struct ApiKey {
+    path: PathBuf,
+    keys: RwLock<Vec<SigningKey>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct SigningKey {
+    pub(crate) kid: String,
+    secret: String, // Hex encoded
+    created: String,
+    #[serde(default)]
+    retired: bool,
+}
+
+impl SigningKey {
+    fn generate() -> SigningKey {
+        let mut rng = rand::thread_rng();
+        SigningKey {
+            kid: hex::encode(rng.gen::<[u8; 8]>()),
+            secret: hex::encode(rng.gen::<[u8; 32]>()),
+            created: Local::now().to_rfc2822(),
+            retired: false,
+        }
+    }
+
+    pub(crate) fn secret(&self) -> Vec<u8> {
+        hex::decode(&self.secret).expect("Keys are checked when they are loaded")
+    }
+
+    // Anyone could sign tokens with an empty or short secret
+    fn check(&self) -> io::Result<()> {
+        match hex::decode(&self.secret) {
+            Ok(s) if s.len() >= 32 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Signing key {} is invalid.", self.kid),
+            )),
+        }
+    }
+
+    // Everything but the secret
+    pub(crate) fn json(&self) -> Value {
+        json!({"kid": self.kid, "created": self.created, "retired": self.retired})
+    }
+}
+
+impl ApiKey {
+    // Loads the keys, and creates one if there is none to sign tokens with
+    pub(crate) fn open(consts: &ZKConfig) -> io::Result<ApiKey> {
+        let path = consts.data_file(&consts.jwt_keys_file, KEYS_FILE);
+        let keys: Vec<SigningKey> = match read_to_string(&path) {
+            Ok(c) => serde_json::from_str(&c).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for k in keys.iter() {
+            k.check()?;
+        }
+        let key = ApiKey {
+            path,
+            keys: RwLock::new(keys),
+        };
+        if key.keys.read().unwrap().iter().all(|k| k.retired) {
+            key.rotate()?;
+        }
+        Ok(key)
+    }
+
+    // Only readable by the server, as anyone with a key can sign in as any user
+    fn save(&self, keys: &[SigningKey]) -> io::Result<()> {
+        let c = serde_json::to_string_pretty(keys).map_err(io::Error::other)?;
+        write_private(&self.path, &c)
+    }
+
+    pub(crate) fn list(&self) -> Vec<SigningKey> {
+        self.keys.read().unwrap().clone()
+    }
+
+    // The key new tokens are signed with
+    pub(crate) fn current(&self) -> SigningKey {
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .rev()
+            .find(|k| !k.retired)
+            .cloned()
+            .expect("There is no key to sign tokens with.")
+    }
+
+    // A key tokens are accepted for
+    pub(crate) fn find(&self, kid: &str) -> Option<SigningKey> {
+        let keys = self.keys.read().unwrap();
+        keys.iter().find(|k| k.kid == kid && !k.retired).cloned()
+    }
+
+    // Adds a new key, that signs all tokens from now on
+    pub(crate) fn rotate(&self) -> io::Result<SigningKey> {
+        let key = SigningKey::generate();
+        let mut keys = self.keys.write().unwrap();
+        keys.push(key.clone());
+        self.save(&keys)?;
+        Ok(key)
+    }
+
+    // Tokens signed with a retired key are rejected. The current key can't be retired.
+    pub(crate) fn retire(&self, kid: &str) -> io::Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        let current = keys.iter().rposition(|k| !k.retired);
+        match keys.iter().position(|k| k.kid == kid) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No such key.")),
+            Some(i) if Some(i) == current => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The current key can't be retired.",
+            )),
+            Some(i) => {
+                keys[i].retired = true;
+                self.save(&keys)
+            }
+        }
+    }
+}
+
+// Key private cookies are encrypted with, unless Rocket's secret_key is set. Rocket would pick a
+// new one on every start, which ends all sessions.
+pub(crate) fn cookie_key(consts: &ZKConfig) -> io::Result<String> {
+    let path = consts.data_file(&consts.cookie_key_file, COOKIE_KEY_FILE);
+    let key = match read_to_string(&path) {
+        Ok(k) => k.trim().to_string(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+            write_private(&path, &key)?;
+            key
+        }
+        Err(e) => return Err(e),
+    };
+    match hex::decode(&key) {
+        Ok(k) if k.len() == 32 => Ok(key),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The cookie key is invalid.",
+        )),
+    }
+}
+
+// Written to a temporary file first, which only the server can read from the start
+pub(crate) fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    remove_file(&tmp).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(content.as_bytes())?;
+    rename(&tmp, path)
+}
 
 // pub(crate) struct FileCount(pub(crate) AtomicUsize);
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub(crate) struct ZKConfig {
     pub(crate) static_files_location: Option<String>,
     pub(crate) cors: bool,
@@ -17,4 +203,270 @@ pub(crate) struct ZKConfig {
     pub(crate) hostname: String,
     pub(crate) admin_password: String,
     pub(crate) path: String,
+    pub(crate) committer_name: Option<String>, // Committer of all commits made by the server
+    pub(crate) committer_email: Option<String>,
+    pub(crate) sync_interval: Option<u64>, // Seconds between syncs of all repositories with a remote
+    pub(crate) commit_delay: Option<u64>,  // Seconds without changes before saves are committed
+    pub(crate) users_file: Option<String>, // Location of the user accounts
+    pub(crate) jwt_keys_file: Option<String>, // Location of the keys tokens are signed with
+    pub(crate) cookie_key_file: Option<String>, // Location of the key cookies are encrypted with
+    pub(crate) revoked_tokens_file: Option<String>, // Location of the tokens revoked before expiry
+}
+
+impl ZKConfig {
+    // Files of the server are kept next to the users directories, unless configured otherwise
+    pub(crate) fn data_file(&self, configured: &Option<String>, default: &str) -> PathBuf {
+        match configured {
+            Some(f) => PathBuf::from(f),
+            None => {
+                Path::new(self.repo_files_location.trim_end_matches('/')).with_file_name(default)
+            }
+        }
+    }
+}
+
+// Tokens and sessions that were revoked before they expired, and when an admin last ended all
+// sessions of a user. Entries are dropped once the tokens they concern expired, as those are
+// rejected anyway.
+pub(crate) struct Revocations {
+    path: PathBuf,
+    revoked: Mutex<Revoked>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Revoked {
+    #[serde(default)]
+    tokens: HashMap<String, i64>, // Refresh tokens that were used, with their expiration time
+    #[serde(default)]
+    sessions: HashMap<String, i64>, // Ended sessions, with the expiration time of their tokens
+    #[serde(default)]
+    users: HashMap<String, i64>, // Tokens of the user issued before then are rejected
+}
+
+impl Revocations {
+    pub(crate) fn open(consts: &ZKConfig) -> io::Result<Revocations> {
+        let path = consts.data_file(&consts.revoked_tokens_file, REVOKED_FILE);
+        let revoked = match read_to_string(&path) {
+            Ok(c) => serde_json::from_str(&c).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Revoked::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Revocations {
+            path,
+            revoked: Mutex::new(revoked),
+        })
+    }
+
+    // Revokes a refresh token once it is used, and returns whether it already was. A used refresh
+    // token was stolen or replayed, so its whole session is ended too.
+    pub(crate) fn revoke(&self, claims: &Claims) -> io::Result<bool> {
+        self.update(|revoked, now| {
+            let reused = revoked
+                .tokens
+                .insert(claims.get_jti(), claims.get_exp())
+                .is_some();
+            if reused {
+                revoked
+                    .sessions
+                    .insert(claims.get_sid(), session_expiry(now));
+            }
+            reused
+        })
+    }
+
+    pub(crate) fn end_session(&self, claims: &Claims) -> io::Result<()> {
+        self.update(|revoked, now| {
+            revoked
+                .sessions
+                .insert(claims.get_sid(), session_expiry(now));
+        })
+    }
+
+    pub(crate) fn end_sessions(&self, user: &str) -> io::Result<()> {
+        self.update(|revoked, now| {
+            revoked.users.insert(user.to_string(), now);
+        })
+    }
+
+    // Issue times are whole seconds. Tokens issued in the second their sessions were ended are still
+    // accepted, so logging in right after isn't refused.
+    pub(crate) fn is_revoked(&self, claims: &Claims) -> bool {
+        let revoked = self.revoked.lock().unwrap();
+        revoked.sessions.contains_key(&claims.get_sid())
+            || revoked
+                .users
+                .get(&claims.get_sub())
+                .is_some_and(|t| claims.get_iat() < *t)
+    }
+
+    // Changes the revocations and saves them, while holding the lock
+    fn update<T>(&self, change: impl FnOnce(&mut Revoked, i64) -> T) -> io::Result<T> {
+        let mut revoked = self.revoked.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let valid = |exp: i64| exp + LEEWAY as i64 > now;
+        revoked.tokens.retain(|_, exp| valid(*exp));
+        revoked.sessions.retain(|_, exp| valid(*exp));
+        revoked.users.retain(|_, t| valid(session_expiry(*t)));
+        let result = change(&mut revoked, now);
+        let c = serde_json::to_string(&*revoked).map_err(io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        write(&tmp, c)?;
+        rename(&tmp, &self.path)?;
+        Ok(result)
+    }
+}
+
+// The latest expiration time of tokens issued until `time`
+fn session_expiry(time: i64) -> i64 {
+    time + chrono::Duration::days(REFRESH_TOKEN_DAYS).num_seconds()
+}
+
+// Paths changed by each user that were not committed yet, and when they last changed
+#[derive(Clone, Default)]
+pub(crate) struct PendingCommits(Arc<Mutex<Pending>>);
+
+#[derive(Default)]
+struct Pending {
+    changes: HashMap<String, (Instant, BTreeSet<PathBuf>)>,
+    failures: HashMap<String, u32>, // Failed attempts in a row to commit the changes of a user
+}
+
+impl PendingCommits {
+    pub(crate) fn add(&self, user: &str, paths: &[PathBuf]) {
+        let mut pending = self.0.lock().unwrap();
+        let entry = pending
+            .changes
+            .entry(user.to_string())
+            .or_insert_with(|| (Instant::now(), BTreeSet::new()));
+        entry.0 = Instant::now();
+        entry.1.extend(paths.iter().cloned());
+    }
+
+    pub(crate) fn take(&self, user: &str) -> Vec<PathBuf> {
+        let mut pending = self.0.lock().unwrap();
+        pending
+            .changes
+            .remove(user)
+            .map(|(_, p)| p.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    // Puts back changes that could not be committed. They are tried again after a delay that
+    // doubles with every failure, up to an hour.
+    pub(crate) fn retry(&self, user: &str, paths: &[PathBuf]) {
+        self.add(user, paths);
+        let mut pending = self.0.lock().unwrap();
+        *pending.failures.entry(user.to_string()).or_default() += 1;
+    }
+
+    pub(crate) fn committed(&self, user: &str) {
+        self.0.lock().unwrap().failures.remove(user);
+    }
+
+    // Users that did not change anything for `delay`, and whose last attempt to commit did not fail
+    // too recently
+    pub(crate) fn idle(&self, delay: Duration) -> Vec<String> {
+        let pending = self.0.lock().unwrap();
+        pending
+            .changes
+            .iter()
+            .filter(|(u, (t, _))| {
+                let backoff = match pending.failures.get(*u) {
+                    Some(f) => Duration::from_secs((1 << (*f).min(12)).min(3600)),
+                    None => Duration::ZERO,
+                };
+                t.elapsed() >= delay + backoff
+            })
+            .map(|(u, _)| u.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Revocations;
+    use super::ZKConfig;
+    use crate::serializables::Claims;
+    use chrono::Duration;
+    use chrono::Utc;
+    use figment::providers::Format;
+    use figment::providers::Toml;
+    use figment::Figment;
+    use rocket::serde::json::serde_json;
+    use rocket::serde::json::serde_json::json;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    fn config(base: &Path) -> ZKConfig {
+        create_dir_all(base.join("notes")).unwrap();
+        Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                path = "/api"
+                cors = false
+                repo_files_location = "{}"
+                hostname = "localhost"
+                admin_password = "secret"
+                "#,
+                base.join("notes").display()
+            )))
+            .extract()
+            .unwrap()
+    }
+
+    fn token(user: &str, sid: &str) -> Claims {
+        Claims::default()
+            .set_sub(user)
+            .set_sid(sid)
+            .set_iat_exp_nbf(Duration::minutes(5))
+    }
+
+    #[test]
+    fn refresh_token_reuse_ends_session() {
+        let base = env::temp_dir().join(format!("zk-reuse-{}", std::process::id()));
+        let revocations = Revocations::open(&config(&base)).unwrap();
+        let refresh = token("alice", "s1").set_refresh(true);
+        let access = token("alice", "s1");
+        assert!(!revocations.revoke(&refresh).unwrap());
+        assert!(!revocations.is_revoked(&access));
+        assert!(revocations.revoke(&refresh).unwrap());
+        assert!(revocations.is_revoked(&access));
+        assert!(!revocations.is_revoked(&token("alice", "s2")));
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn end_session() {
+        let base = env::temp_dir().join(format!("zk-logout-{}", std::process::id()));
+        let config = config(&base);
+        let revocations = Revocations::open(&config).unwrap();
+        revocations.end_session(&token("alice", "s1")).unwrap();
+        assert!(revocations.is_revoked(&token("alice", "s1")));
+        assert!(!revocations.is_revoked(&token("alice", "s2")));
+        // Kept across restarts
+        let revocations = Revocations::open(&config).unwrap();
+        assert!(revocations.is_revoked(&token("alice", "s1").set_refresh(true)));
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn end_sessions_of_user() {
+        let base = env::temp_dir().join(format!("zk-sessions-{}", std::process::id()));
+        let revocations = Revocations::open(&config(&base)).unwrap();
+        let issued = |user: &str, seconds_ago: i64| -> Claims {
+            let iat = Utc::now().timestamp() - seconds_ago;
+            serde_json::from_value(json!({
+                "iss": "localhost", "sub": user, "aud": "", "iat": iat, "nbf": iat, "exp": iat + 300,
+            }))
+            .unwrap()
+        };
+        revocations.end_sessions("alice").unwrap();
+        assert!(revocations.is_revoked(&issued("alice", 10)));
+        assert!(!revocations.is_revoked(&issued("bob", 10)));
+        // A new login right after is accepted
+        assert!(!revocations.is_revoked(&issued("alice", 0)));
+        remove_dir_all(&base).ok();
+    }
 }
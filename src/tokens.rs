@@ -2,7 +2,10 @@ use crate::serializables::Claims;
 use crate::state::ApiKey;
 use crate::state::ZKConfig;
 use jsonwebtoken::decode;
+use jsonwebtoken::decode_header;
 use jsonwebtoken::encode;
+use jsonwebtoken::errors::Error;
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::DecodingKey;
 use jsonwebtoken::EncodingKey;
 use jsonwebtoken::Header;
@@ -10,14 +13,26 @@ use jsonwebtoken::TokenData;
 use jsonwebtoken::Validation;
 use rocket::State;
 
+// Tokens are accepted for this many seconds after they expired, to allow for clock skew
+pub(crate) const LEEWAY: u64 = 180;
+// Access tokens are short-lived. Clients get new ones with the refresh token, until that expires
+// or the user logs out.
+pub(crate) const ACCESS_TOKEN_MINUTES: i64 = 15;
+pub(crate) const REFRESH_TOKEN_DAYS: i64 = 30;
+
 pub(crate) fn issue_token(
     claims: &Claims,
     key: &ApiKey,
 ) -> Result<String, jsonwebtoken::errors::Error> {
+    let signing = key.current();
+    let header = Header {
+        kid: Some(signing.kid.clone()),
+        ..Default::default()
+    };
     encode(
-        &Header::default(),
+        &header,
         claims,
-        &EncodingKey::from_secret(&key.0.clone().into_bytes()),
+        &EncodingKey::from_secret(&signing.secret()),
     )
 }
 
@@ -27,15 +42,20 @@ pub(crate) fn validate_token(
     consts: &State<ZKConfig>,
 ) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
     let validation = Validation {
-        leeway: 180,
+        leeway: LEEWAY,
         validate_nbf: true,
         validate_exp: true,
         iss: Some(consts.hostname.to_string()),
         ..Default::default()
     };
+    // Tokens without a key id were signed by a key that is gone
+    let signing = decode_header(token)?
+        .kid
+        .and_then(|kid| key.find(&kid))
+        .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
     decode(
         &token,
-        &DecodingKey::from_secret(&key.0.clone().into_bytes()),
+        &DecodingKey::from_secret(&signing.secret()),
         &validation,
     )
 }
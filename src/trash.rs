@@ -0,0 +1,219 @@
+use crate::filesystem_interact::is_hidden_path;
+use crate::filesystem_interact::keep_extension;
+use crate::filesystem_interact::Entry;
+use crate::filesystem_interact::FType;
+use crate::functions::calculate_id;
+use crate::links::normalize;
+use chrono::Local;
+use rocket::serde::json::serde_json;
+use std::fs::create_dir_all;
+use std::fs::read_to_string;
+use std::fs::remove_dir_all;
+use std::fs::rename;
+use std::fs::write;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Deleted files and directories are moved to <user>/.trash/<id>/<name>. The index keeps track of
+// where they came from. Both are part of the repository, so every change shows up in the history.
+pub(crate) const TRASH_DIR: &str = ".trash";
+const TRASH_INDEX: &str = ".trash/index.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct TrashItem {
+    pub(crate) id: String,
+    pub(crate) path: PathBuf, // Original path, relative to the users directory
+    pub(crate) deleted: String,
+    pub(crate) ftype: FType,
+}
+
+impl TrashItem {
+    // Location of the item in the trash, relative to the users directory
+    pub(crate) fn trash_path(&self) -> PathBuf {
+        Path::new(TRASH_DIR)
+            .join(&self.id)
+            .join(self.path.file_name().unwrap_or_default())
+    }
+
+    pub(crate) fn trash_dir(&self) -> PathBuf {
+        Path::new(TRASH_DIR).join(&self.id)
+    }
+}
+
+pub(crate) fn index_path() -> PathBuf {
+    PathBuf::from(TRASH_INDEX)
+}
+
+// The index is part of the repository, so it can be changed by a push or a sync. Items that would
+// point outside of the trash, or be restored outside of the users directory, are refused.
+pub(crate) fn list(basepath: &Path) -> io::Result<Vec<TrashItem>> {
+    let items: Vec<TrashItem> = match read_to_string(basepath.join(TRASH_INDEX)) {
+        Ok(c) => serde_json::from_str(&c).map_err(io::Error::other)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    items.into_iter().map(checked).collect()
+}
+
+fn checked(mut item: TrashItem) -> io::Result<TrashItem> {
+    let valid_id = !item.id.is_empty()
+        && item.id.len() <= 16
+        && item
+            .id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    let path = normalize(&item.path)
+        .filter(|p| p.file_name().is_some() && !is_hidden_path(p) && p.is_relative());
+    match (valid_id, path) {
+        (true, Some(p)) => {
+            item.path = p;
+            Ok(item)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The trash index is corrupt.",
+        )),
+    }
+}
+
+fn save(items: &[TrashItem], basepath: &Path) -> io::Result<()> {
+    create_dir_all(basepath.join(TRASH_DIR))?;
+    let c = serde_json::to_string_pretty(items).map_err(io::Error::other)?;
+    write(basepath.join(TRASH_INDEX), c)
+}
+
+// Moves a file or directory into the trash
+pub(crate) fn trash(entry: &Entry, basepath: &Path) -> io::Result<TrashItem> {
+    let mut items = list(basepath)?;
+    let item = TrashItem {
+        id: format!("{:x}", calculate_id(&entry.url)),
+        path: entry.url.clone(),
+        deleted: Local::now().to_rfc2822(),
+        ftype: entry.ftype.clone(),
+    };
+    create_dir_all(basepath.join(item.trash_dir()))?;
+    rename(&entry.data, basepath.join(item.trash_path()))?;
+    items.push(item.clone());
+    save(&items, basepath)?;
+    Ok(item)
+}
+
+// Moves an item out of the trash, to `target` or its original path. If that path is taken, a free
+// one next to it is chosen. Returns the item and the path it was restored to.
+pub(crate) fn restore(
+    id: &str,
+    target: Option<PathBuf>,
+    basepath: &Path,
+) -> io::Result<(TrashItem, PathBuf)> {
+    let mut items = list(basepath)?;
+    let index = items
+        .iter()
+        .position(|i| i.id == id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such item in trash."))?;
+    let item = items.remove(index);
+    let target = target.map(|t| keep_extension(t, &item.path, &item.ftype));
+    let target = free_path(target.as_ref().unwrap_or(&item.path), basepath);
+    if let Some(parent) = basepath.join(&target).parent() {
+        create_dir_all(parent)?;
+    }
+    rename(basepath.join(item.trash_path()), basepath.join(&target))?;
+    remove_dir_all(basepath.join(item.trash_dir())).ok();
+    save(&items, basepath)?;
+    Ok((item, target))
+}
+
+// Permanently removes one or all items from the trash. Returns the removed items.
+pub(crate) fn purge(id: Option<&str>, basepath: &Path) -> io::Result<Vec<TrashItem>> {
+    let (purged, kept): (Vec<TrashItem>, Vec<TrashItem>) = list(basepath)?
+        .into_iter()
+        .partition(|i| id.is_none_or(|id| i.id == id));
+    if id.is_some() && purged.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No such item in trash.",
+        ));
+    }
+    for item in purged.iter() {
+        remove_dir_all(basepath.join(item.trash_dir()))?;
+    }
+    save(&kept, basepath)?;
+    Ok(purged)
+}
+
+fn free_path(path: &Path, basepath: &Path) -> PathBuf {
+    if !basepath.join(path).exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut i = 1;
+    loop {
+        let name = match i {
+            1 => format!("{} (restored){}", stem, extension),
+            _ => format!("{} (restored {}){}", stem, i, extension),
+        };
+        let candidate = path.with_file_name(name);
+        if !basepath.join(&candidate).exists() {
+            return candidate;
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::purge;
+    use super::restore;
+    use super::TRASH_INDEX;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::remove_dir_all;
+    use std::fs::write;
+    use std::path::PathBuf;
+
+    // Directories of two users. "alice" has a note in the trash, and `index` as trash index
+    fn users(name: &str, index: &str) -> (PathBuf, PathBuf) {
+        let base = env::temp_dir().join(format!("zk-trash-{}-{}", name, std::process::id()));
+        let alice = base.join("notes").join("alice");
+        create_dir_all(alice.join(".trash/1f")).unwrap();
+        create_dir_all(base.join("notes").join("bob")).unwrap();
+        write(alice.join(".trash/1f/pwn.md"), "note").unwrap();
+        write(alice.join(TRASH_INDEX), index).unwrap();
+        (base, alice)
+    }
+
+    #[test]
+    fn restore_outside_of_user_directory() {
+        let index = r#"[{"id":"1f","path":"../bob/pwn.md","deleted":"","ftype":"MDFile"}]"#;
+        let (base, alice) = users("restore", index);
+        assert!(restore("1f", None, &alice).is_err());
+        assert!(!base.join("notes/bob/pwn.md").exists());
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn purge_outside_of_trash() {
+        let index = r#"[{"id":"../../../victim","path":"a.md","deleted":"","ftype":"MDFile"}]"#;
+        let (base, alice) = users("purge", index);
+        create_dir_all(base.join("victim")).unwrap();
+        assert!(purge(None, &alice).is_err());
+        assert!(purge(Some("../../../victim"), &alice).is_err());
+        assert!(base.join("victim").exists());
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn restore_valid_item() {
+        let index = r#"[{"id":"1f","path":"./dir/../pwn.md","deleted":"","ftype":"MDFile"}]"#;
+        let (base, alice) = users("valid", index);
+        let (item, target) = restore("1f", None, &alice).unwrap();
+        assert_eq!(item.path, PathBuf::from("pwn.md"));
+        assert_eq!(target, PathBuf::from("pwn.md"));
+        assert!(alice.join("pwn.md").exists());
+        remove_dir_all(&base).ok();
+    }
+}
@@ -0,0 +1,332 @@
+use crate::state::ZKConfig;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::PasswordHash;
+use argon2::password_hash::PasswordHasher;
+use argon2::password_hash::PasswordVerifier;
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use chrono::Local;
+use rocket::serde::json::serde_json;
+use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Value;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::read_to_string;
+use std::fs::rename;
+use std::fs::write;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const USERS_FILE: &str = "users.json";
+const ADMIN_USER: &str = "admin";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct User {
+    pub(crate) name: String,
+    password: String, // Argon2 hash, in PHC string format
+    #[serde(default)]
+    pub(crate) disabled: bool,
+    #[serde(default)]
+    pub(crate) admin: bool,
+    #[serde(default)]
+    pub(crate) last_login: Option<String>,
+}
+
+impl User {
+    // Everything but the password hash
+    pub(crate) fn json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "disabled": self.disabled,
+            "admin": self.admin,
+            "last_login": self.last_login,
+        })
+    }
+}
+
+pub(crate) struct UserStore {
+    path: PathBuf,
+    users: Mutex<Vec<User>>,
+}
+
+impl UserStore {
+    // Loads the accounts. If there are none yet, one is created for the admin and for every
+    // existing users directory, all with the admin_password.
+    pub(crate) fn open(consts: &ZKConfig) -> io::Result<UserStore> {
+        let path = consts.data_file(&consts.users_file, USERS_FILE);
+        let mut users: Vec<User> = match read_to_string(&path) {
+            Ok(c) => serde_json::from_str(&c).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // Accounts created before there were roles have no admin
+        if !users.iter().any(|u| u.admin) {
+            if let Some(u) = users.iter_mut().find(|u| u.name == ADMIN_USER) {
+                u.admin = true;
+            }
+        }
+        let store = UserStore {
+            path,
+            users: Mutex::new(users),
+        };
+        if store.users.lock().unwrap().is_empty() {
+            store.seed(consts)?;
+        }
+        Ok(store)
+    }
+
+    fn seed(&self, consts: &ZKConfig) -> io::Result<()> {
+        // Otherwise anyone could sign in as admin with an empty password
+        if consts.admin_password.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "admin_password must be set to create the first accounts.",
+            ));
+        }
+        let mut names = vec![ADMIN_USER.to_string()];
+        for entry in read_dir(&consts.repo_files_location)?.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && !name.starts_with('.') && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        create_dir_all(Path::new(&consts.repo_files_location).join(ADMIN_USER))?;
+        let mut users = self.users.lock().unwrap();
+        for name in names {
+            users.push(User {
+                admin: name == ADMIN_USER,
+                password: hash_password(&consts.admin_password)?,
+                name,
+                disabled: false,
+                last_login: None,
+            });
+        }
+        self.save(&users)
+    }
+
+    // Written to a temporary file first, so a crash can't leave a truncated store behind
+    fn save(&self, users: &[User]) -> io::Result<()> {
+        let c = serde_json::to_string_pretty(users).map_err(io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        write(&tmp, c)?;
+        rename(&tmp, &self.path)
+    }
+
+    pub(crate) fn list(&self) -> Vec<User> {
+        self.users.lock().unwrap().clone()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<User> {
+        let users = self.users.lock().unwrap();
+        users.iter().find(|u| u.name == name).cloned()
+    }
+
+    // Whether `name` is an enabled account with the given password
+    pub(crate) fn verify(&self, name: &str, password: &str) -> bool {
+        let user = match self.get(name) {
+            Some(u) if !u.disabled => u,
+            _ => return false,
+        };
+        PasswordHash::new(&user.password)
+            .map(|h| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &h)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn record_login(&self, name: &str) -> io::Result<()> {
+        self.change(name, |u| u.last_login = Some(Local::now().to_rfc2822()))
+    }
+
+    pub(crate) fn create(&self, name: &str, password: &str, admin: bool) -> io::Result<()> {
+        let user = User {
+            name: name.to_string(),
+            password: hash_password(password)?,
+            disabled: false,
+            admin,
+            last_login: None,
+        };
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.name == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "User already exists.",
+            ));
+        }
+        users.push(user);
+        self.save(&users)
+    }
+
+    pub(crate) fn update(
+        &self,
+        name: &str,
+        password: Option<&str>,
+        disabled: Option<bool>,
+        admin: Option<bool>,
+    ) -> io::Result<()> {
+        let password = password.map(hash_password).transpose()?;
+        let mut users = self.users.lock().unwrap();
+        let mut changed = users.clone();
+        let user = changed
+            .iter_mut()
+            .find(|u| u.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such user."))?;
+        if let Some(p) = password {
+            user.password = p;
+        }
+        user.disabled = disabled.unwrap_or(user.disabled);
+        user.admin = admin.unwrap_or(user.admin);
+        // Nobody could manage the accounts anymore
+        if !changed.iter().any(|u| u.admin && !u.disabled) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "There has to be at least one enabled admin.",
+            ));
+        }
+        self.save(&changed)?;
+        *users = changed;
+        Ok(())
+    }
+
+    // Removes the account. The users directory is kept.
+    pub(crate) fn delete(&self, name: &str) -> io::Result<()> {
+        let mut users = self.users.lock().unwrap();
+        let index = users
+            .iter()
+            .position(|u| u.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such user."))?;
+        users.remove(index);
+        self.save(&users)
+    }
+
+    fn change<F>(&self, name: &str, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut User),
+    {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|u| u.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such user."))?;
+        f(user);
+        self.save(&users)
+    }
+}
+
+fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserStore;
+    use crate::state::ZKConfig;
+    use figment::providers::Format;
+    use figment::providers::Toml;
+    use figment::Figment;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::fs::remove_dir_all;
+    use std::io;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    // Configuration for the users directories in `base`, where "alice" already has one
+    fn config(base: &Path, admin_password: &str) -> ZKConfig {
+        create_dir_all(base.join("notes").join("alice")).unwrap();
+        Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                path = "/api"
+                cors = false
+                repo_files_location = "{}"
+                hostname = "localhost"
+                admin_password = "{}"
+                "#,
+                base.join("notes").display(),
+                admin_password
+            )))
+            .extract()
+            .unwrap()
+    }
+
+    fn base(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("zk-users-{}-{}", name, std::process::id()))
+    }
+
+    fn kind(result: io::Result<()>) -> io::ErrorKind {
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn seed_accounts() {
+        let base = base("seed");
+        assert!(UserStore::open(&config(&base, "")).is_err());
+        let users = UserStore::open(&config(&base, "secret")).unwrap();
+        assert!(users.get("admin").unwrap().admin);
+        assert!(!users.get("alice").unwrap().admin);
+        assert!(users.verify("admin", "secret"));
+        assert!(users.verify("alice", "secret"));
+        assert!(!users.verify("alice", ""));
+        assert!(!users.verify("bob", "secret"));
+        assert!(base.join("notes").join("admin").is_dir());
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn create_and_delete() {
+        let base = base("create");
+        let config = config(&base, "secret");
+        let users = UserStore::open(&config).unwrap();
+        users.create("bob", "pw", false).unwrap();
+        assert!(users.verify("bob", "pw"));
+        assert!(!users.verify("bob", "secret"));
+        let duplicate = users.create("bob", "other", true);
+        assert_eq!(kind(duplicate), io::ErrorKind::AlreadyExists);
+        assert!(users.verify("bob", "pw"));
+        // Kept across restarts
+        let users = UserStore::open(&config).unwrap();
+        assert!(users.verify("bob", "pw"));
+        users.delete("bob").unwrap();
+        assert!(users.get("bob").is_none());
+        assert!(!users.verify("bob", "pw"));
+        assert_eq!(kind(users.delete("bob")), io::ErrorKind::NotFound);
+        remove_dir_all(&base).ok();
+    }
+
+    #[test]
+    fn disable_and_change_roles() {
+        let base = base("update");
+        let users = UserStore::open(&config(&base, "secret")).unwrap();
+        users.update("alice", Some("new"), None, None).unwrap();
+        assert!(users.verify("alice", "new"));
+        assert!(!users.verify("alice", "secret"));
+        users.update("alice", None, Some(true), None).unwrap();
+        assert!(!users.verify("alice", "new"));
+        users.update("alice", None, Some(false), None).unwrap();
+        assert!(users.verify("alice", "new"));
+
+        // There is always an enabled admin
+        let last = users.update("admin", None, Some(true), None);
+        assert_eq!(kind(last), io::ErrorKind::InvalidInput);
+        let last = users.update("admin", None, None, Some(false));
+        assert_eq!(kind(last), io::ErrorKind::InvalidInput);
+        assert!(users.get("admin").is_some_and(|u| u.admin && !u.disabled));
+        users.update("alice", None, None, Some(true)).unwrap();
+        users.update("admin", None, None, Some(false)).unwrap();
+        assert!(!users.get("admin").unwrap().admin);
+        assert!(users.get("alice").unwrap().admin);
+
+        let missing = users.update("bob", None, Some(true), None);
+        assert_eq!(kind(missing), io::ErrorKind::NotFound);
+        remove_dir_all(&base).ok();
+    }
+}